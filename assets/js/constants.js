@@ -9,7 +9,22 @@ export const APP_DIV = document.getElementById("app");
 export const WS_ENDPOINT = APP_DIV.dataset.wsEndpoint;
 export const ROOM_ID = APP_DIV.dataset.roomId;
 export const CURRENT_USER_ID = APP_DIV.dataset.userId;
-export const WEBSOCKET = new WebSocket(`${WS_ENDPOINT}/${ROOM_ID}`);
+export const INITIAL_OWNER_ID = APP_DIV.dataset.ownerId || null;
+export const RECONNECT_DELAY_MS = 2000;
+// the server gave up on this client, e.g too many protocol errors
+export const POLICY_CLOSE_CODE = 1008;
+// shape of the messages this script understands
+export const PROTOCOL_VERSION = "hearts.v2";
+// pages without a room, e.g the replay, don't connect
+export let WEBSOCKET = ROOM_ID
+  ? new WebSocket(`${WS_ENDPOINT}/${ROOM_ID}`, PROTOCOL_VERSION)
+  : null;
+
+// imports of WEBSOCKET are live bindings, they all get the new socket
+export function reconnectWebSocket() {
+  WEBSOCKET = new WebSocket(`${WS_ENDPOINT}/${ROOM_ID}`, PROTOCOL_VERSION);
+  return WEBSOCKET;
+}
 
 // divs
 export const STATE_DIV = document.querySelector(".gameState");
@@ -19,3 +34,8 @@ export const PLAYER_BOTTOM_DIV = document.querySelector("#playerBottom");
 export const PLAYER_LEFT_DIV = document.querySelector("#playerLeft");
 export const PLAYER_TOP_DIV = document.querySelector("#playerTop");
 export const PLAYER_RIGHT_DIV = document.querySelector("#playerRight");
+export const PLAYER_ERROR_P = document.querySelector("#playerError");
+export const NAME_FORM = document.querySelector("#nameForm");
+export const LEAVE_BUTTON = document.querySelector("#leaveRoom");
+export const TRICKS_BUTTON = document.querySelector("#showTricks");
+export const TRICK_HISTORY_DIV = document.querySelector("#trickHistory");
@@ -0,0 +1,78 @@
+// keeps the lobby table in sync with the rooms, the feed starts with all of them
+const LOBBY_BODY = document.querySelector("#lobby tbody");
+const STATUS_ORDER = ["waiting", "playing", "done"];
+let rooms = new Map();
+
+function statusText(room) {
+  switch (room.status) {
+    case "waiting":
+      return `Waiting (${room.seats.filter((s) => s).length}/4)`;
+    case "playing":
+      return "In progress";
+    default:
+      return "Done";
+  }
+}
+
+function settingsText(settings) {
+  let end =
+    settings.end_condition === "scoreThreshold"
+      ? `First to ${settings.score_threshold}`
+      : `${settings.hands} hands`;
+  return `${end}, ${settings.timeout_secs}s per turn`;
+}
+
+function renderLobby() {
+  LOBBY_BODY.innerHTML = "";
+  // open tables first
+  let sorted = [...rooms.values()].sort(
+    (a, b) =>
+      STATUS_ORDER.indexOf(a.status) - STATUS_ORDER.indexOf(b.status) ||
+      a.id.localeCompare(b.id),
+  );
+  for (const room of sorted) {
+    let row = document.createElement("tr");
+    let link = document.createElement("a");
+    link.href = `/room/${room.id}`;
+    link.innerText = `#${room.id.substring(0, 8)}`;
+    let cells = [
+      link,
+      statusText(room),
+      room.seats.map((seat) => seat?.name || "-").join(", "),
+      `${room.viewers}`,
+      settingsText(room.settings),
+    ];
+    for (const content of cells) {
+      let cell = document.createElement("td");
+      if (typeof content === "string") {
+        cell.innerText = content;
+      } else {
+        cell.appendChild(content);
+      }
+      row.appendChild(cell);
+    }
+    LOBBY_BODY.appendChild(row);
+  }
+}
+
+const events = new EventSource("/lobby/events");
+events.onopen = () => {
+  // the feed starts over with every room after a reconnect
+  rooms = new Map();
+  renderLobby();
+};
+events.onmessage = (evt) => {
+  let update = JSON.parse(evt.data);
+  if (update.room) {
+    rooms.set(update.room.id, update.room);
+  } else if (update.removed) {
+    rooms.delete(update.removed);
+  } else if (update.reset) {
+    // the page fell behind, like after a reconnect
+    rooms = new Map(update.reset.map((room) => [room.id, room]));
+  }
+  renderLobby();
+};
+events.onerror = () => {
+  console.log("lobby feed lost, reconnecting...");
+};
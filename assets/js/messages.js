@@ -11,6 +11,10 @@ export function sendGetCurrentState() {
   sendStringMessageType("getCurrentState");
 }
 
+export function sendResume(lastSeq) {
+  sendStringMessageType({ resume: { last_seq: lastSeq } });
+}
+
 export function sendReplaceCards(cards) {
   let obj = {
     replaceCards: cards,
@@ -25,6 +29,10 @@ export function sendPlayCard(card) {
   sendStringMessageType(obj);
 }
 
+export function sendGetTrickHistory() {
+  sendStringMessageType("getTrickHistory");
+}
+
 export function sendGetCards() {
   sendStringMessageType("getCards");
 }
@@ -35,3 +43,42 @@ export function sendJoin() {
 export function sendJoinBot() {
   sendStringMessageType("joinBot");
 }
+
+export function sendRemoveBot(botId) {
+  sendStringMessageType({ removeBot: botId });
+}
+
+export function sendKick(userId) {
+  sendStringMessageType({ kick: userId });
+}
+
+export function sendTransferOwnership(userId) {
+  sendStringMessageType({ transferOwnership: userId });
+}
+
+export function sendRequestRematch() {
+  sendStringMessageType("requestRematch");
+}
+
+export function sendAcceptRematch() {
+  sendStringMessageType("acceptRematch");
+}
+
+export function sendLeaveSeat() {
+  sendStringMessageType("leaveSeat");
+}
+
+export function sendLeave() {
+  sendStringMessageType("leave");
+}
+
+export function sendCloseRoom() {
+  sendStringMessageType("closeRoom");
+}
+
+export function sendSetName(name) {
+  let obj = {
+    setName: name,
+  };
+  sendStringMessageType(obj);
+}
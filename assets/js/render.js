@@ -13,8 +13,87 @@ import {
   PLAYER_TOP_DIV,
   PLAYER_LEFT_DIV,
   PLAYER_RIGHT_DIV,
+  PLAYER_ERROR_P,
+  TRICK_HISTORY_DIV,
 } from "./constants.js";
-import { sendJoin, sendJoinBot } from "./messages.js";
+import {
+  sendJoin,
+  sendJoinBot,
+  sendRemoveBot,
+  sendKick,
+  sendTransferOwnership,
+  sendCloseRoom,
+  sendLeaveSeat,
+  sendRequestRematch,
+  sendAcceptRematch,
+} from "./messages.js";
+
+// display names by user id
+const playerNames = new Map();
+const botIds = new Set();
+let connectedIds = new Set();
+
+export function setPlayerNames(users) {
+  for (const user of users) {
+    if (user) {
+      playerNames.set(user.id, user.name);
+      if (user.bot) {
+        botIds.add(user.id);
+      }
+    }
+  }
+}
+
+export function setConnectedPlayers(userIds) {
+  connectedIds = new Set(userIds);
+  renderPresence();
+}
+
+export function setPlayerConnected(userId, connected) {
+  if (connected) {
+    connectedIds.add(userId);
+  } else {
+    connectedIds.delete(userId);
+  }
+  renderPresence();
+}
+
+export function renderBotControlled(userId, botControlled) {
+  let playerDiv = findPlayerDivById(userId);
+  if (playerDiv) {
+    playerDiv.classList.toggle("botControlled", botControlled);
+  }
+}
+
+// grey out absent players. bots are always there
+export function renderPresence() {
+  for (const { div } of getOrderedPlayerDivs(null)) {
+    let playerId = div.dataset.id;
+    let absent =
+      playerId && !botIds.has(playerId) && !connectedIds.has(playerId);
+    div.classList.toggle("disconnected", Boolean(absent));
+  }
+}
+
+export function renderPlayerName(user) {
+  setPlayerNames([user]);
+  let playerDiv = findPlayerDivById(user.id);
+  if (playerDiv) {
+    playerDiv.querySelector(".playerName").innerText = user.name;
+  }
+}
+
+export function renderPlayerError(error) {
+  // e.g "nameTaken" or { game: "STATE_ERROR" }
+  let message =
+    typeof error === "string"
+      ? error
+      : Object.entries(error)
+        .map(([kind, detail]) => `${kind}: ${detail}`)
+        .join(", ");
+  PLAYER_ERROR_P.innerText = message;
+  PLAYER_ERROR_P.classList.remove("d-none");
+}
 
 export function renderState(mode, customizeStateDiv = (_stateDiv) => { }) {
   // reset state
@@ -38,7 +117,7 @@ export function renderState(mode, customizeStateDiv = (_stateDiv) => { }) {
     case PLAYING_HAND:
       break;
     case END:
-      message = "End game."; // todo print the winner
+      message = "End game.";
       break;
     default:
       throw `unknown mode ${mode}`;
@@ -58,7 +137,74 @@ export function renderState(mode, customizeStateDiv = (_stateDiv) => { }) {
   customizeStateDiv(STATE_DIV);
 }
 
-export function renderWaitingForPlayers(mode, seats) {
+// rematch is { players, votes } where players are the seated user ids
+export function renderEnd(
+  mode,
+  winners,
+  gameId = null,
+  session = null,
+  rematch = null,
+) {
+  if (mode != END) {
+    throw `invalid call to renderEnd: ${mode}`;
+  }
+  const nameOf = (id) => playerNames.get(id) || id.substring(0, 8);
+  renderState(mode, (stateDiv) => {
+    let span = document.createElement("span");
+    span.classList = "d-block";
+    let names = winners.map(nameOf);
+    span.innerText =
+      names.length > 1 ? `Winners: ${names.join(", ")}` : `Winner: ${names[0]}`;
+    stateDiv.appendChild(span);
+    if (gameId) {
+      let replayLink = document.createElement("a");
+      replayLink.classList = "d-block";
+      replayLink.href = `/game/${gameId}/replay`;
+      replayLink.innerText = "Replay";
+      stateDiv.appendChild(replayLink);
+    }
+    if (session && session.matches > 1) {
+      let sessionSpan = document.createElement("span");
+      sessionSpan.classList = "d-block";
+      let scores = session.player_scores.map(
+        (ps) => `${nameOf(ps.player_id)} ${ps.score}`,
+      );
+      sessionSpan.innerText = `After ${session.matches} matches: ${scores.join(", ")}`;
+      stateDiv.appendChild(sessionSpan);
+    }
+    if (rematch) {
+      let humans = rematch.players.filter((id) => !botIds.has(id));
+      let votesSpan = document.createElement("span");
+      votesSpan.classList = "d-block";
+      if (rematch.votes.length) {
+        votesSpan.innerText = `Rematch: ${rematch.votes.length}/${humans.length}`;
+        stateDiv.appendChild(votesSpan);
+      }
+      if (
+        humans.includes(CURRENT_USER_ID) &&
+        !rematch.votes.includes(CURRENT_USER_ID)
+      ) {
+        let rematchButton = document.createElement("button");
+        rematchButton.classList = "d-block";
+        if (rematch.votes.length) {
+          rematchButton.innerText = "Accept rematch";
+          rematchButton.onclick = () => sendAcceptRematch();
+        } else {
+          rematchButton.innerText = "Rematch";
+          rematchButton.onclick = () => sendRequestRematch();
+        }
+        stateDiv.appendChild(rematchButton);
+      }
+    }
+  });
+}
+
+export function renderWaitingForPlayers(
+  mode,
+  seats,
+  users = [],
+  isOwner = false,
+) {
   if (mode != WAITING_FOR_PLAYERS) {
     throw `invalid call to renderWaitingForPlayers: ${mode}`;
   }
@@ -71,16 +217,57 @@ export function renderWaitingForPlayers(mode, seats) {
       joinButton.onclick = sendJoin;
       joinButton.innerText = "Join";
       divButton.appendChild(joinButton);
+    } else {
+      let leaveSeatButton = document.createElement("button");
+      leaveSeatButton.onclick = sendLeaveSeat;
+      leaveSeatButton.classList = "me-1";
+      leaveSeatButton.innerText = "Leave seat";
+      divButton.appendChild(leaveSeatButton);
+    }
+    if (isOwner) {
+      let addBotButton = document.createElement("button");
+      addBotButton.onclick = sendJoinBot;
+      addBotButton.classList = "me-1";
+      addBotButton.innerText = "Add bot";
+      divButton.appendChild(addBotButton);
     }
-    let addBotButton = document.createElement("button");
-    addBotButton.onclick = sendJoinBot;
-    addBotButton.classList = "me-1";
-    addBotButton.innerText = "Add bot";
-    divButton.appendChild(addBotButton);
     stateDiv.appendChild(divButton);
+    if (isOwner) {
+      renderAdminActions(stateDiv, users);
+    }
   });
   renderPlayers(seats);
 }
+
+function renderAdminActions(stateDiv, users) {
+  const addButton = (parentDiv, text, onClick) => {
+    let button = document.createElement("button");
+    button.classList = "me-1";
+    button.innerText = text;
+    button.onclick = onClick;
+    parentDiv.appendChild(button);
+  };
+  for (const user of users) {
+    if (!user || user.id === CURRENT_USER_ID) {
+      continue;
+    }
+    let divUser = document.createElement("div");
+    divUser.classList = "d-block";
+    if (user.bot) {
+      addButton(divUser, `Remove ${user.name}`, () => sendRemoveBot(user.id));
+    } else {
+      addButton(divUser, `Kick ${user.name}`, () => sendKick(user.id));
+      addButton(divUser, `Make ${user.name} owner`, () =>
+        sendTransferOwnership(user.id),
+      );
+    }
+    stateDiv.appendChild(divUser);
+  }
+  let divClose = document.createElement("div");
+  divClose.classList = "d-block";
+  addButton(divClose, "Close room", sendCloseRoom);
+  stateDiv.appendChild(divClose);
+}
 export function renderPlayers(seats) {
   let orderedPlayerDivs = getOrderedPlayerDivs(seats);
   for (const { id, div } of orderedPlayerDivs) {
@@ -145,6 +332,27 @@ export function renderNewHand(mode, playerIds, playerScores, currentPlayerId) {
     playerScores,
   );
 }
+// tricks of the current hand, the last one first
+export function renderTrickHistory(tricks, visible = true) {
+  TRICK_HISTORY_DIV.innerHTML = "";
+  TRICK_HISTORY_DIV.classList.toggle("d-none", !visible);
+  if (!tricks.length) {
+    TRICK_HISTORY_DIV.innerText = "No trick played yet.";
+    return;
+  }
+  for (const trick of [...tricks].reverse()) {
+    let p = document.createElement("p");
+    for (const played of trick.cards) {
+      renderCard(p, played.card, false);
+    }
+    let span = document.createElement("span");
+    let name = (id) => playerNames.get(id) || id.substring(0, 8);
+    span.innerText = `led by ${name(trick.leader)}, taken by ${name(trick.winner)} (${trick.points} pts)`;
+    p.appendChild(span);
+    TRICK_HISTORY_DIV.appendChild(p);
+  }
+}
+
 export function renderStack(mode, stack) {
   STACK_DIV.innerHTML = "";
   renderState(mode, (_) => {
@@ -158,10 +366,17 @@ export function renderReceivedCards(
   onClick = (_cardElt, _clickedCard, _isSelected) => {
     throw "not implemented";
   },
+  legalMoves = null,
 ) {
   USERCARDS_DIV.innerHTML = "";
   for (const card of cards) {
-    renderCard(USERCARDS_DIV, card, true, onClick);
+    // without legal moves, the server decides
+    let legal =
+      !card || !legalMoves || legalMoves.includes(card.position_in_deck);
+    renderCard(USERCARDS_DIV, card, legal, onClick);
+    if (!legal) {
+      USERCARDS_DIV.lastChild.classList.add("kard-illegal");
+    }
   }
 }
 
@@ -259,8 +474,13 @@ export function renderPlayer(playerDiv, currentPlayer = false, playerId) {
   } else {
     seatDiv.classList.add("filledSeat");
   }
+  playerDiv.classList.toggle(
+    "disconnected",
+    Boolean(playerId && !botIds.has(playerId) && !connectedIds.has(playerId)),
+  );
   let playerNameP = playerDiv.querySelector(".playerName");
-  playerNameP.innerText = playerId?.substring(0, 8) || "-";
+  playerNameP.innerText =
+    playerNames.get(playerId) || playerId?.substring(0, 8) || "-";
   if (currentPlayer) {
     // reset previous current user
     let previousPlayerDiv = document.querySelector(
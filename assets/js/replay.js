@@ -0,0 +1,102 @@
+import {
+  PLAYING_HAND,
+  APP_DIV,
+  USERCARDS_DIV,
+  PLAYER_BOTTOM_DIV,
+} from "./constants.js";
+import {
+  setPlayerNames,
+  renderPlayers,
+  renderStack,
+  renderScores,
+  renderCard,
+  setConnectedPlayers,
+} from "./render.js";
+
+const GAME_ID = APP_DIV.dataset.gameId;
+const STEPS = parseInt(APP_DIV.dataset.steps);
+const PREVIOUS_BUTTON = document.querySelector("#previousStep");
+const NEXT_BUTTON = document.querySelector("#nextStep");
+const STEP_P = document.querySelector("#replayStep");
+
+let currentStep = 0;
+
+const describe = (step, name) => {
+  let [kind, detail] =
+    typeof step.event === "string"
+      ? [step.event, null]
+      : Object.entries(step.event)[0];
+  switch (kind) {
+    case "dealt":
+      return `hand ${detail.hand} dealt`;
+    case "exchanged":
+      return `${name(step.user_id)} passed ${detail.cards.map((c) => c.emoji).join(" ")} to ${name(detail.to_player_id)}`;
+    case "played":
+      return `${name(step.user_id)} played ${detail.emoji}`;
+    case "trickTaken":
+      return `${name(detail.winner)} took the trick (${detail.points} pts)`;
+    case "handScored":
+      return `end of hand ${detail.hand}`;
+    case "ended":
+      return `game over`;
+    default:
+      return kind;
+  }
+};
+
+async function showStep(step) {
+  let response = await fetch(`/game/${GAME_ID}/replay/${step}`);
+  if (!response.ok) {
+    STEP_P.innerText = `could not load step ${step}`;
+    return;
+  }
+  let replayStep = await response.json();
+  currentStep = replayStep.step;
+
+  let playerIds = replayStep.players.map((p) => p.id);
+  let names = new Map(replayStep.players.map((p) => [p.id, p.name]));
+  setPlayerNames(replayStep.players);
+  // nobody is absent from a replay
+  setConnectedPlayers(playerIds);
+  renderPlayers(playerIds);
+
+  let { stack, player_scores, current_scores } =
+    replayStep.update.updateStackAndScore;
+  renderStack(PLAYING_HAND, stack);
+  renderScores(current_scores, player_scores);
+
+  // the hand of the player at the bottom of the table
+  USERCARDS_DIV.innerHTML = "";
+  let bottomIdx = playerIds.indexOf(PLAYER_BOTTOM_DIV.dataset.id);
+  for (const card of replayStep.cards[bottomIdx] || []) {
+    renderCard(USERCARDS_DIV, card, false);
+  }
+
+  STEP_P.innerText = `${replayStep.step + 1}/${STEPS}: ${describe(replayStep, (id) => names.get(id) || "-")}`;
+  PREVIOUS_BUTTON.disabled = currentStep === 0;
+  NEXT_BUTTON.disabled = currentStep === STEPS - 1;
+}
+
+PREVIOUS_BUTTON.onclick = (evt) => {
+  evt.preventDefault();
+  if (currentStep > 0) {
+    showStep(currentStep - 1);
+  }
+};
+
+NEXT_BUTTON.onclick = (evt) => {
+  evt.preventDefault();
+  if (currentStep < STEPS - 1) {
+    showStep(currentStep + 1);
+  }
+};
+
+document.onkeydown = (evt) => {
+  if (evt.key === "ArrowLeft") {
+    PREVIOUS_BUTTON.click();
+  } else if (evt.key === "ArrowRight") {
+    NEXT_BUTTON.click();
+  }
+};
+
+showStep(currentStep);
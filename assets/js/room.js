@@ -10,8 +10,15 @@ import {
   EXCHANGE_CARDS,
   PLAYING_HAND,
   WEBSOCKET,
+  RECONNECT_DELAY_MS,
+  POLICY_CLOSE_CODE,
+  reconnectWebSocket,
   CURRENT_USER_ID,
   END,
+  NAME_FORM,
+  INITIAL_OWNER_ID,
+  LEAVE_BUTTON,
+  TRICKS_BUTTON,
 } from "./constants.js";
 import {
   renderState,
@@ -25,53 +32,154 @@ import {
   renderScores,
   resetCurrentScores,
   renderPlayers,
+  setPlayerNames,
+  renderPlayerName,
+  renderPlayerError,
+  renderEnd,
+  setConnectedPlayers,
+  setPlayerConnected,
+  renderBotControlled,
+  renderTrickHistory,
 } from "./render.js";
 import {
   sendGetCards,
   sendGetCurrentState,
+  sendResume,
   sendPlayCard,
   sendReplaceCards,
+  sendSetName,
+  sendLeave,
+  sendGetTrickHistory,
 } from "./messages.js";
 
 let mode = WAITING_FOR_MESSAGE;
 let playerIds = [];
+let seatUsers = [];
+let ownerId = INITIAL_OWNER_ID;
+let leaving = false;
+let closing = false;
+// sequence number of the last public event handled
+let lastSeq = null;
+let resuming = false;
 let currentPlayerId = null;
 let currentPlayerCards = null;
 let cardsToExchange = null;
+let showTricks = false;
+// what renderEnd needs, to render it again when votes come in
+let end = null;
 renderState(mode);
 
-WEBSOCKET.onopen = sendGetCurrentState;
-WEBSOCKET.onclose = () => {
-  // websocket is closed.
-  console.log("Connection is closed...");
+// rooms without owner can be administered by anyone
+const isOwner = () => !ownerId || ownerId === CURRENT_USER_ID;
+const leaveRoom = () => {
+  closing = true;
+  window.location.href = "/";
 };
 
-WEBSOCKET.onerror = (err) => {
-  console.error(`ws error ${JSON.stringify(err)}`);
+LEAVE_BUTTON.onclick = (evt) => {
+  evt.preventDefault();
+  leaving = true;
+  sendLeave();
 };
 
-WEBSOCKET.onmessage = (evt) => {
+TRICKS_BUTTON.onclick = (evt) => {
+  evt.preventDefault();
+  showTricks = !showTricks;
+  if (showTricks) {
+    sendGetTrickHistory();
+  } else {
+    renderTrickHistory([], false);
+  }
+};
+
+NAME_FORM.onsubmit = (evt) => {
+  evt.preventDefault();
+  sendSetName(new FormData(NAME_FORM).get("name"));
+};
+
+const setupWebSocket = (socket) => {
+  socket.onopen = () => {
+    // after a reconnect, only ask for what we missed
+    if (lastSeq === null) {
+      sendGetCurrentState();
+    } else {
+      sendResume(lastSeq);
+    }
+  };
+  socket.onclose = (evt) => {
+    // websocket is closed.
+    console.log(`Connection is closed: ${evt.code} ${evt.reason}`);
+    if (!closing && evt.code !== POLICY_CLOSE_CODE) {
+      setTimeout(
+        () => setupWebSocket(reconnectWebSocket()),
+        RECONNECT_DELAY_MS,
+      );
+    }
+  };
+  socket.onerror = (err) => {
+    console.error(`ws error ${JSON.stringify(err)}`);
+  };
+  socket.onmessage = handleMessage;
+};
+
+// false if the message was already handled or some before it are missing
+const checkSeq = (roomMessage) => {
+  if (roomMessage.toUserId) {
+    if (roomMessage.msgType.state || roomMessage.msgType.waitingForPlayers) {
+      // the state is up to date with the events up to seq
+      lastSeq = roomMessage.seq;
+      resuming = false;
+    }
+    return true;
+  }
+  if (lastSeq !== null && roomMessage.seq <= lastSeq) {
+    // e.g replayed for another tab
+    return false;
+  }
+  if (lastSeq !== null && roomMessage.seq > lastSeq + 1) {
+    if (!resuming) {
+      resuming = true;
+      sendResume(lastSeq);
+    }
+    return false;
+  }
+  lastSeq = roomMessage.seq;
+  resuming = false;
+  return true;
+};
+
+const handleMessage = (evt) => {
   const roomMessage = JSON.parse(evt.data);
   console.log(roomMessage);
 
+  if (!checkSeq(roomMessage)) {
+    return;
+  }
+
   if (roomMessage.msgType instanceof String) {
     console.log("roomMessage is a string type ", roomMessage.msgType);
   } else {
     if (roomMessage.msgType.waitingForPlayers) {
       mode = WAITING_FOR_PLAYERS;
-      playerIds = roomMessage.msgType.waitingForPlayers;
-      renderWaitingForPlayers(mode, playerIds);
+      seatUsers = roomMessage.msgType.waitingForPlayers;
+      setPlayerNames(seatUsers);
+      playerIds = seatUsers.map((user) => user?.id || null);
+      renderWaitingForPlayers(mode, playerIds, seatUsers, isOwner());
     } else if (roomMessage.msgType.joined) {
       if (mode != WAITING_FOR_PLAYERS) {
         throw `joined event and invalid mode ${mode}`;
       }
+      let user = roomMessage.msgType.joined;
+      setPlayerNames([user]);
       let emptySeat = playerIds.indexOf(null);
-      playerIds[emptySeat] = roomMessage.msgType.joined;
-      renderWaitingForPlayers(mode, playerIds);
+      playerIds[emptySeat] = user.id;
+      seatUsers[emptySeat] = user;
+      renderWaitingForPlayers(mode, playerIds, seatUsers, isOwner());
     } else if (roomMessage.msgType.newHand) {
       mode = NEW_HAND;
-      let { player_ids_in_order, player_scores, current_player_id } =
+      let { player_ids_in_order, players, player_scores, current_player_id } =
         roomMessage.msgType.newHand;
+      setPlayerNames(players);
       playerIds = player_ids_in_order;
       currentPlayerId = current_player_id;
       renderNewHand(mode, playerIds, player_scores, currentPlayerId);
@@ -79,8 +187,9 @@ WEBSOCKET.onmessage = (evt) => {
         sendGetCards();
       }
     } else if (roomMessage.msgType.receiveCards) {
-      currentPlayerCards = roomMessage.msgType.receiveCards;
-      renderReceivedCards(currentPlayerCards, handleCardClicked);
+      let { cards, legal_moves } = roomMessage.msgType.receiveCards;
+      currentPlayerCards = cards;
+      renderReceivedCards(currentPlayerCards, handleCardClicked, legal_moves);
     } else if (roomMessage.msgType.nextPlayerToReplaceCards) {
       mode = EXCHANGE_CARDS;
       let { current_player_id } = roomMessage.msgType.nextPlayerToReplaceCards;
@@ -96,12 +205,12 @@ WEBSOCKET.onmessage = (evt) => {
       sendGetCards();
     } else if (roomMessage.msgType.nextPlayerToPlay) {
       mode = PLAYING_HAND;
-      let { current_cards, current_player_id, stack } =
+      let { current_cards, legal_moves, current_player_id, stack } =
         roomMessage.msgType.nextPlayerToPlay;
       currentPlayerId = current_player_id;
       if (current_cards) {
         currentPlayerCards = current_cards;
-        renderReceivedCards(current_cards, handleCardClicked);
+        renderReceivedCards(current_cards, handleCardClicked, legal_moves);
       }
       renderStack(mode, stack);
       renderNextPlayer(mode, currentPlayerId);
@@ -110,8 +219,38 @@ WEBSOCKET.onmessage = (evt) => {
         roomMessage.msgType.updateStackAndScore;
       renderStack(mode, stack);
       renderScores(current_scores, player_scores);
+      if (showTricks) {
+        sendGetTrickHistory();
+      }
     } else if (roomMessage.msgType === "timedOut") {
       sendGetCurrentState();
+    } else if (roomMessage.msgType === "roomClosed") {
+      leaveRoom();
+    } else if (roomMessage.msgType.kicked) {
+      if (roomMessage.msgType.kicked === CURRENT_USER_ID) {
+        leaveRoom();
+      } else {
+        sendGetCurrentState();
+      }
+    } else if (roomMessage.msgType.left) {
+      if (leaving && roomMessage.msgType.left === CURRENT_USER_ID) {
+        leaveRoom();
+      } else {
+        sendGetCurrentState();
+      }
+    } else if (roomMessage.msgType.connectedPlayers) {
+      setConnectedPlayers(roomMessage.msgType.connectedPlayers);
+    } else if (roomMessage.msgType.playerConnected) {
+      setPlayerConnected(roomMessage.msgType.playerConnected, true);
+    } else if (roomMessage.msgType.playerDisconnected) {
+      setPlayerConnected(roomMessage.msgType.playerDisconnected, false);
+    } else if (roomMessage.msgType.botTakeover) {
+      renderBotControlled(roomMessage.msgType.botTakeover, true);
+    } else if (roomMessage.msgType.seatReclaimed) {
+      renderBotControlled(roomMessage.msgType.seatReclaimed, false);
+    } else if (roomMessage.msgType.ownerChanged) {
+      ownerId = roomMessage.msgType.ownerChanged;
+      sendGetCurrentState();
     } else if (roomMessage.msgType.state) {
       // todo set mode
       let state = roomMessage.msgType.state;
@@ -119,11 +258,19 @@ WEBSOCKET.onmessage = (evt) => {
       currentPlayerId = state.current_player_id;
       currentPlayerCards = state.current_cards;
 
+      setPlayerNames(state.players);
       renderPlayers(state.player_scores.map((ps) => ps.player_id));
+      if (showTricks) {
+        renderTrickHistory(state.tricks);
+      }
 
       switch (mode) {
         case PLAYING_HAND:
-          renderReceivedCards(currentPlayerCards, handleCardClicked);
+          renderReceivedCards(
+            currentPlayerCards,
+            handleCardClicked,
+            state.legal_moves,
+          );
           renderStack(mode, state.current_stack);
           renderScores(state.current_scores, state.player_scores);
           renderNextPlayer(mode, currentPlayerId);
@@ -136,22 +283,71 @@ WEBSOCKET.onmessage = (evt) => {
           resetCurrentScores(); // todo maybe not needed
 
           break;
-        case END:
+        case END: {
           renderScores(state.current_scores, state.player_scores);
           resetCurrentScores(); // todo maybe not needed
-          renderState(mode);
+          let lowest = Math.min(...state.player_scores.map((ps) => ps.score));
+          end = {
+            winners: state.player_scores
+              .filter((ps) => ps.score === lowest)
+              .map((ps) => ps.player_id),
+            gameId: null,
+            session: state.session,
+            rematch: {
+              players: state.players.map((p) => p.id),
+              votes: state.rematch_votes,
+            },
+          };
+          renderEnd(mode, end.winners, end.gameId, end.session, end.rematch);
           break;
+        }
         default:
           throw `state error: unknown mode ${mode}`;
       }
+    } else if (roomMessage.msgType.trickHistory) {
+      if (showTricks) {
+        renderTrickHistory(roomMessage.msgType.trickHistory);
+      }
+    } else if (roomMessage.msgType.protocolError) {
+      let { code, detail } = roomMessage.msgType.protocolError;
+      console.error(`protocol error ${code}: ${detail}`);
+    } else if (roomMessage.msgType.nameChanged) {
+      renderPlayerName(roomMessage.msgType.nameChanged);
+    } else if (roomMessage.msgType.playerError) {
+      if (leaving) {
+        // e.g the seat is kept during a game, leave the page anyway
+        leaveRoom();
+      }
+      renderPlayerError(roomMessage.msgType.playerError);
     } else if (roomMessage.msgType.end) {
       mode = END;
+      let { player_scores, winners, game_id, session } =
+        roomMessage.msgType.end;
       resetCurrentScores();
-      renderState(mode);
+      renderScores(
+        player_scores.map((p) => {
+          return { player_id: p.player_id, score: 0 };
+        }),
+        player_scores,
+      );
+      end = {
+        winners,
+        gameId: game_id,
+        session,
+        rematch: { players: player_scores.map((p) => p.player_id), votes: [] },
+      };
+      renderEnd(mode, end.winners, end.gameId, end.session, end.rematch);
+    } else if (roomMessage.msgType.rematchVotes) {
+      if (mode === END && end) {
+        end.rematch.votes = roomMessage.msgType.rematchVotes;
+        renderEnd(mode, end.winners, end.gameId, end.session, end.rematch);
+      }
     }
   }
 };
 
+setupWebSocket(WEBSOCKET);
+
 function handleCardClicked(cardElt, clickedCard, isSelected) {
   //toggle
   switch (mode) {
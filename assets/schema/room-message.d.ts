@@ -0,0 +1,172 @@
+// generated by `cargo run -- schema`, do not edit
+
+export type GameError = "UNKNOWN_CARD" | "FORBIDDEN_MOVE" | "PLAYER_DOESNT_HAVE_CARD" | "HEART_NEVER_PLAYED_BEFORE" | "MUST_USE_START_CARD" | "MUST_PLAY_SAME_KIND" | "CANNOT_START_WITH_QUEEN_OR_HEART" | "STATE_ERROR";
+
+export type PlayedCard = {
+  card: PlayerCard;
+  player_id: string;
+};
+
+export type PlayerCard = {
+  emoji: string;
+  position_in_deck: number;
+  type_card: TypeCard;
+};
+
+export type PlayerErrorKind = "nameTaken" | "notOwner" | {
+  game: GameError;
+} | {
+  invalidName: string;
+} | {
+  unknownUser: string;
+};
+
+export type PlayerState = {
+  player_id: string;
+  score: number;
+};
+
+export type ProtocolErrorCode = "invalidMessage" | "unsupportedFrame";
+
+export type RoomMessageType = "join" | "timedOut" | "joinBot" | "getCards" | "getCurrentState" | "getTrickHistory" | "closeRoom" | "roomClosed" | "requestRematch" | "acceptRematch" | "leaveSeat" | "leave" | {
+  startHand: {
+    current_player_id: string;
+    uuid: string;
+  };
+} | {
+  joined: User;
+} | {
+  viewerJoined: string;
+} | {
+  receiveCards: {
+    cards: (PlayerCard | null)[];
+    legal_moves?: number[] | null;
+  };
+} | {
+  replaceCards: PlayerCard[];
+} | {
+  newHand: {
+    current_hand: number;
+    current_player_id: string;
+    hands: number;
+    player_ids_in_order: string[];
+    player_scores: PlayerState[];
+    players: User[];
+    uuid: string;
+  };
+} | {
+  nextPlayerToReplaceCards: {
+    current_player_id: string;
+    uuid: string;
+  };
+} | {
+  nextPlayerToPlay: {
+    current_cards?: (PlayerCard | null)[] | null;
+    current_player_id: string;
+    legal_moves?: number[] | null;
+    stack: (PlayerCard | null)[];
+    uuid: string;
+  };
+} | {
+  updateStackAndScore: {
+    current_scores?: PlayerState[] | null;
+    player_scores: PlayerState[];
+    stack: (PlayerCard | null)[];
+  };
+} | {
+  end: {
+    game_id?: string | null;
+    player_scores: PlayerState[];
+    session: SessionScores;
+    winners: string[];
+  };
+} | {
+  playerError: PlayerErrorKind;
+} | {
+  play: PlayerCard;
+} | {
+  trickHistory: Trick[];
+} | {
+  state: {
+    current_cards: (PlayerCard | null)[];
+    current_hand: number;
+    current_player_id?: string | null;
+    current_scores: PlayerState[];
+    current_stack: (PlayerCard | null)[];
+    hands: number;
+    legal_moves?: number[] | null;
+    mode: string;
+    player_scores: PlayerState[];
+    players: User[];
+    rematch_votes: string[];
+    session: SessionScores;
+    tricks: Trick[];
+  };
+} | {
+  waitingForPlayers: (User | null)[];
+} | {
+  setName: string;
+} | {
+  nameChanged: User;
+} | {
+  removeBot: string;
+} | {
+  kick: string;
+} | {
+  transferOwnership: string;
+} | {
+  kicked: string;
+} | {
+  ownerChanged: string;
+} | {
+  rematchVotes: string[];
+} | {
+  left: string;
+} | {
+  playerConnected: string;
+} | {
+  playerDisconnected: string;
+} | {
+  connectedPlayers: string[];
+} | {
+  botTakeover: string;
+} | {
+  seatReclaimed: string;
+} | {
+  resume: {
+    last_seq: number;
+  };
+} | {
+  protocolError: {
+    code: ProtocolErrorCode;
+    detail: string;
+  };
+};
+
+export type SessionScores = {
+  matches: number;
+  player_scores: PlayerState[];
+};
+
+export type Trick = {
+  cards: PlayedCard[];
+  leader: string;
+  points: number;
+  winner: string;
+};
+
+export type TypeCard = "HEART" | "SPADE" | "DIAMOND" | "CLUB";
+
+export type User = {
+  bot: boolean;
+  id: string;
+  is_guest: boolean;
+  name: string;
+};
+
+export type RoomMessage = {
+  fromUserId?: string | null;
+  msgType: RoomMessageType;
+  seq?: number | null;
+  toUserId?: string | null;
+};
@@ -0,0 +1,7 @@
+create table if not exists sessions (
+    id TEXT PRIMARY KEY NOT NULL,
+    session TEXT NOT NULL,
+    expires INTEGER
+);
+
+create index if not exists sessions_expires on sessions (expires);
@@ -0,0 +1,3 @@
+alter table users add column password_hash TEXT;
+
+create unique index if not exists users_registered_name on users (name) where is_guest = 0;
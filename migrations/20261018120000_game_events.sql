@@ -0,0 +1,23 @@
+create table if not exists games (
+    id BLOB PRIMARY KEY NOT NULL,
+    room_id BLOB NOT NULL,
+    players TEXT NOT NULL,
+    settings TEXT NOT NULL,
+    started_at INTEGER NOT NULL,
+    ended_at INTEGER
+);
+
+create table if not exists game_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    game_id BLOB NOT NULL,
+    user_id BLOB,
+    kind TEXT NOT NULL,
+    event TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (game_id)
+        REFERENCES games (id)
+);
+
+create index if not exists game_events_game_id on game_events (game_id);
+
+alter table rooms add column game_id BLOB;
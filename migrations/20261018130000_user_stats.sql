@@ -0,0 +1,10 @@
+create table if not exists user_stats (
+    user_id BLOB PRIMARY KEY NOT NULL,
+    games_played INTEGER NOT NULL,
+    wins INTEGER NOT NULL,
+    total_points INTEGER NOT NULL,
+    moons_shot INTEGER NOT NULL,
+    queens_taken INTEGER NOT NULL,
+    FOREIGN KEY (user_id)
+        REFERENCES users (id)
+);
@@ -0,0 +1,21 @@
+create table if not exists ratings (
+    user_id BLOB PRIMARY KEY NOT NULL,
+    rating REAL NOT NULL,
+    games_played INTEGER NOT NULL,
+    FOREIGN KEY (user_id)
+        REFERENCES users (id)
+);
+
+create table if not exists rating_history (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id BLOB NOT NULL,
+    game_id BLOB NOT NULL,
+    rating REAL NOT NULL,
+    created_at INTEGER NOT NULL,
+    FOREIGN KEY (user_id)
+        REFERENCES users (id),
+    FOREIGN KEY (game_id)
+        REFERENCES games (id)
+);
+
+create index if not exists rating_history_user_id on rating_history (user_id);
@@ -0,0 +1 @@
+alter table rooms add column session TEXT;
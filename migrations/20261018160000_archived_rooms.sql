@@ -0,0 +1,10 @@
+create table if not exists archived_rooms (
+    id BLOB PRIMARY KEY NOT NULL,
+    state TEXT NOT NULL,
+    bots TEXT NOT NULL,
+    settings TEXT,
+    owner BLOB,
+    game_id BLOB,
+    session TEXT,
+    archived_at INTEGER NOT NULL
+);
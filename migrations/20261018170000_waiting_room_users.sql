@@ -0,0 +1,24 @@
+-- waiting rooms used to seat user ids, they now seat users
+update rooms
+set state = json_object('WaitingForPlayers', json((
+    select json_group_array(json(seat)) from (
+        select case
+            when s.type = 'null' then null
+            else json_object(
+                'id', s.value,
+                'is_guest', json(case when u.is_guest then 'true' else 'false' end),
+                -- unknown players get a bot name, like when they join
+                'name', coalesce(u.name, 'Bot' || abs(random() % 100000000)),
+                'bot', json(case
+                    when exists (select 1 from json_each(rooms.bots) b where b.value = s.value)
+                    then 'true' else 'false' end)
+            )
+        end as seat
+        from json_each(rooms.state, '$.WaitingForPlayers') s
+        left join users u on lower(hex(u.id)) = replace(s.value, '-', '')
+        order by s.key
+    )
+)))
+where exists (
+    select 1 from json_each(rooms.state, '$.WaitingForPlayers') where type = 'text'
+);
@@ -9,16 +9,52 @@ pub static SERVICE_CONFIG_VOLUME: &str = "SERVICE_CONFIG_VOLUME";
 pub static SERVICE_DATA_VOLUME: &str = "SERVICE_DATA_VOLUME";
 pub static SERVICE_APPLICATION_NAME: &str = "SERVICE_APPLICATION_NAME";
 pub static SERVICE_COLLECTION_NAME: &str = "SERVICE_COLLECTION_NAME";
+pub static ROOM_IDLE_SECS: &str = "ROOM_IDLE_SECS";
 pub static COOKIE: &str = "HeartsCookie";
 pub static USER_ID: &str = "X_USER_ID";
+pub static SESSION_EXPIRY_SECS: u64 = 60 * 60 * 24 * 30;
+pub static SESSION_CLEANUP_INTERVAL_SECS: u64 = 60 * 60;
+// rooms nobody is connected to are archived after that long
+pub static DEFAULT_ROOM_IDLE_SECS: u64 = 60 * 30;
+// finished rooms don't wait that long, the grace covers page reloads
+pub static FINISHED_ROOM_GRACE_SECS: u64 = 60;
+pub static ROOM_REAPER_INTERVAL_SECS: u64 = 60;
+pub static PASSWORD_MIN_LEN: usize = 8;
 // this may have to be increased
 // broadcast channels are super weird and hard to debug
 // thus if there's an issue, it probably means you have to increase this.
-pub static ABRITRATRY_CHANNEL_CAPACITY: usize = 8;
+// only the room task and the timeout bots listen to this one.
+pub static ABRITRATRY_CHANNEL_CAPACITY: usize = 32;
+// a socket lagging behind loses the oldest public events and gets the current state
+pub static PUBLIC_CHANNEL_CAPACITY: usize = 32;
+// a lobby page that far behind loses the oldest room updates
+pub static LOBBY_CHANNEL_CAPACITY: usize = 64;
+// a socket with that many private messages pending is closed
+pub static USER_CHANNEL_CAPACITY: usize = 32;
+// public events kept to replay them to clients that missed some
+pub static EVENT_LOG_SIZE: usize = 64;
+// invalid messages tolerated before closing the socket
+pub static MAX_PROTOCOL_ERRORS: usize = 5;
+pub static CLOSE_TIMEOUT_SECS: u64 = 1;
+pub static SCHEMA_DIR: &str = "assets/schema";
+pub static SCHEMA_PATH: &str = "assets/schema/room-message.schema.json";
+pub static TYPESCRIPT_PATH: &str = "assets/schema/room-message.d.ts";
 pub static DEFAULT_HANDS: u8 = 3;
 pub static TIMEOUT_SECS: usize = 5;
 pub static BOT_SLEEP_SECS: u64 = 1;
 pub static COMPUTE_SCORE_DELAY_SECS: u64 = 1;
+// a bot plays for a seated player disconnected for longer than this
+pub static DISCONNECT_GRACE_SECS: u64 = 15;
+pub static MAX_HANDS: u8 = 20;
+pub static MAX_TIMEOUT_SECS: u64 = 300;
+pub static MAX_DELAY_SECS: u64 = 10;
+pub static DEFAULT_SCORE_THRESHOLD: usize = 100;
+pub static MAX_SCORE_THRESHOLD: usize = 500;
+pub static DEFAULT_RATING: f64 = 1500.;
+// spread over the pairwise comparisons of a game
+pub static RATING_K_FACTOR: f64 = 32.;
+pub static LEADERBOARD_SIZE: i64 = 20;
+pub static RATING_HISTORY_SIZE: i64 = 10;
 
 #[cfg(test)]
 mod test {}
@@ -1,26 +1,153 @@
-use std::{borrow::Cow, collections::HashSet, error::Error, fmt::Display, sync::Arc};
+use std::{
+    borrow::Cow,
+    collections::{HashMap, HashSet, VecDeque},
+    error::Error,
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
 use arraystring::ArrayString;
 use async_broadcast::{InactiveReceiver, Sender};
 use dashmap::DashMap;
 use lib_hearts::{
-    Game, GameError, PlayerState, PositionInDeck, TypeCard, PLAYER_CARD_SIZE, PLAYER_NUMBER,
+    Game, GameError, PlayerState, PositionInDeck, TypeCard, NUMBER_REPLACEABLE_CARDS,
+    PLAYER_CARD_SIZE, PLAYER_NUMBER,
 };
+use schemars::JsonSchema;
 use serde_derive::{Deserialize, Serialize};
 use sqlx::{Pool, Sqlite};
-use tokio::{sync::RwLock, task::JoinHandle};
+use tokio::{
+    sync::{mpsc, RwLock},
+    task::JoinHandle,
+};
 use uuid::Uuid;
 
-#[derive(Serialize, Copy, PartialEq, Clone, Debug, Deserialize)]
+use crate::schema::{GameErrorDef, PlayerStateDef, TypeCardDef};
+
+#[derive(Serialize, Copy, PartialEq, Clone, Debug, Deserialize, JsonSchema)]
 pub struct PlayerCard {
+    #[schemars(with = "TypeCardDef")]
     pub type_card: TypeCard,
+    #[schemars(with = "String")]
     pub emoji: CardEmoji,
     pub position_in_deck: PositionInDeck,
 }
 
 pub type StaticStr = Cow<'static, str>;
 
-#[derive(Clone, Serialize, PartialEq, Deserialize, Debug)]
+#[derive(Serialize, Copy, PartialEq, Clone, Debug, Deserialize, JsonSchema)]
+pub struct PlayedCard {
+    pub player_id: UserId,
+    pub card: PlayerCard,
+}
+
+// a completed trick of the current hand
+#[derive(Serialize, PartialEq, Clone, Debug, Deserialize, JsonSchema)]
+pub struct Trick {
+    // in play order, the first card was led
+    pub cards: Vec<PlayedCard>,
+    pub leader: UserId,
+    pub winner: UserId,
+    pub points: usize,
+}
+
+// scores summed over the matches played in a room
+#[derive(Serialize, PartialEq, Clone, Debug, Default, Deserialize, JsonSchema)]
+pub struct SessionScores {
+    pub matches: u32,
+    #[schemars(with = "Vec<PlayerStateDef>")]
+    pub player_scores: Vec<PlayerState>,
+}
+
+#[derive(Serialize, PartialEq, Clone, Debug, Deserialize)]
+pub struct DealtCards {
+    pub player_id: UserId,
+    pub cards: Vec<PlayerCard>,
+}
+
+// what happened during a game, stored in game_events
+#[derive(Serialize, PartialEq, Clone, Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum GameEvent {
+    // players are in the order of the game
+    Started {
+        room_id: Uuid,
+        players: [User; PLAYER_NUMBER],
+        settings: RoomSettings,
+    },
+    Dealt {
+        hand: u8,
+        cards: [DealtCards; PLAYER_NUMBER],
+    },
+    Exchanged {
+        to_player_id: UserId,
+        cards: [PlayerCard; NUMBER_REPLACEABLE_CARDS],
+    },
+    Played(PlayerCard),
+    TrickTaken(Trick),
+    HandScored {
+        hand: u8,
+        player_scores: [PlayerState; PLAYER_NUMBER],
+    },
+    Ended {
+        player_scores: [PlayerState; PLAYER_NUMBER],
+        winners: Vec<UserId>,
+    },
+}
+
+// the table after an event of a finished game
+#[derive(Serialize, PartialEq, Clone, Debug)]
+pub struct ReplayStep {
+    pub step: usize,
+    pub steps: usize,
+    pub hand: u8,
+    pub players: [User; PLAYER_NUMBER],
+    pub user_id: Option<UserId>,
+    pub event: GameEvent,
+    // cards left in every hand, in the order of the players
+    pub cards: [Vec<PlayerCard>; PLAYER_NUMBER],
+    // always UpdateStackAndScore, the room page renders it the same way
+    pub update: RoomMessageType,
+}
+
+// totals over the finished games of a user
+#[derive(Serialize, Deserialize, Default, Copy, Clone, Debug, PartialEq)]
+pub struct UserStats {
+    pub games_played: i64,
+    pub wins: i64,
+    pub total_points: i64,
+    pub moons_shot: i64,
+    pub queens_taken: i64,
+}
+
+#[derive(Serialize, PartialEq, Clone, Debug)]
+pub struct UserProfile {
+    pub user: User,
+    pub stats: UserStats,
+    pub average_points: f64,
+}
+
+// registered players only, guests and bots are not rated
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct Rating {
+    pub user: User,
+    pub rating: f64,
+    pub games_played: i64,
+    // oldest first
+    pub history: Vec<f64>,
+}
+
+#[derive(PartialEq, Clone, Debug)]
+pub struct GameEventRow {
+    pub game_id: Uuid,
+    // the player who did it, none for the dealer
+    pub user_id: Option<UserId>,
+    pub event: GameEvent,
+    pub created_at: i64,
+}
+
+#[derive(Clone, Serialize, PartialEq, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub enum RoomMessageType {
     StartHand {
@@ -30,15 +157,21 @@ pub enum RoomMessageType {
     Join,
     TimedOut,
     JoinBot,
-    Joined(UserId),
+    Joined(User),
     ViewerJoined(UserId),
     GetCards,
-    ReceiveCards([Option<PlayerCard>; PLAYER_CARD_SIZE]),
+    ReceiveCards {
+        cards: [Option<PlayerCard>; PLAYER_CARD_SIZE],
+        // only set for the player whose turn it is to play
+        legal_moves: Option<Vec<PositionInDeck>>,
+    },
     ReplaceCards([PlayerCard; lib_hearts::NUMBER_REPLACEABLE_CARDS]),
     NewHand {
         player_ids_in_order: [UserId; PLAYER_NUMBER],
+        players: [User; PLAYER_NUMBER],
         current_player_id: UserId,
         current_hand: u8,
+        #[schemars(with = "[PlayerStateDef; PLAYER_NUMBER]")]
         player_scores: [PlayerState; PLAYER_NUMBER],
         hands: u8,
         uuid: Uuid,
@@ -50,34 +183,109 @@ pub enum RoomMessageType {
     NextPlayerToPlay {
         current_player_id: UserId,
         current_cards: Option<[Option<PlayerCard>; PLAYER_CARD_SIZE]>,
+        legal_moves: Option<Vec<PositionInDeck>>,
         stack: [Option<PlayerCard>; PLAYER_NUMBER],
         uuid: Uuid,
     },
     UpdateStackAndScore {
         stack: [Option<PlayerCard>; PLAYER_NUMBER],
+        #[schemars(with = "[PlayerStateDef; PLAYER_NUMBER]")]
         player_scores: [PlayerState; PLAYER_NUMBER],
+        #[schemars(with = "Option<[PlayerStateDef; PLAYER_NUMBER]>")]
         current_scores: Option<[PlayerState; PLAYER_NUMBER]>,
     },
     End {
+        #[schemars(with = "[PlayerStateDef; PLAYER_NUMBER]")]
         player_scores: [PlayerState; PLAYER_NUMBER],
+        winners: Vec<UserId>,
+        // to replay the game
+        game_id: Option<Uuid>,
+        session: SessionScores,
     },
-    PlayerError(GameError),
+    PlayerError(PlayerErrorKind),
     Play(PlayerCard),
     GetCurrentState,
+    GetTrickHistory,
+    TrickHistory(Vec<Trick>),
     State {
         mode: StaticStr,
+        #[schemars(with = "[PlayerStateDef; PLAYER_NUMBER]")]
         player_scores: [PlayerState; PLAYER_NUMBER],
+        #[schemars(with = "[PlayerStateDef; PLAYER_NUMBER]")]
         current_scores: [PlayerState; PLAYER_NUMBER],
         current_cards: Box<[Option<PlayerCard>; PLAYER_CARD_SIZE]>,
         current_stack: [Option<PlayerCard>; PLAYER_NUMBER],
         current_hand: u8,
         current_player_id: Option<UserId>,
+        legal_moves: Option<Vec<PositionInDeck>>,
+        tricks: Vec<Trick>,
         hands: u8,
+        players: [User; PLAYER_NUMBER],
+        session: Box<SessionScores>,
+        rematch_votes: Vec<UserId>,
+    },
+    WaitingForPlayers([Option<User>; PLAYER_NUMBER]),
+    SetName(String),
+    NameChanged(User),
+    // owner only
+    RemoveBot(UserId),
+    Kick(UserId),
+    TransferOwnership(UserId),
+    CloseRoom,
+    Kicked(UserId),
+    OwnerChanged(UserId),
+    RoomClosed,
+    // once the game is done, every seated human must accept to play again
+    RequestRematch,
+    AcceptRematch,
+    RematchVotes(Vec<UserId>),
+    // free the seat while waiting for players
+    LeaveSeat,
+    // free the seat and stop viewing the room
+    Leave,
+    Left(UserId),
+    // sent by the socket task, never by the client
+    #[serde(skip_deserializing)]
+    Connected,
+    #[serde(skip_deserializing)]
+    Disconnected,
+    PlayerConnected(UserId),
+    PlayerDisconnected(UserId),
+    ConnectedPlayers(Vec<UserId>),
+    // sent once the grace period of a disconnected player is over
+    #[serde(skip_deserializing)]
+    TakeOverSeat,
+    BotTakeover(UserId),
+    SeatReclaimed(UserId),
+    // replay the events after last_seq, or send the state if too many were missed
+    Resume {
+        last_seq: u64,
+    },
+    // answer to a message the server could not understand
+    ProtocolError {
+        code: ProtocolErrorCode,
+        detail: String,
     },
-    WaitingForPlayers([Option<UserId>; PLAYER_NUMBER]),
 }
 
-#[derive(Clone, Serialize, PartialEq, Deserialize, Debug)]
+#[derive(Copy, Clone, Serialize, PartialEq, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum ProtocolErrorCode {
+    InvalidMessage,
+    UnsupportedFrame,
+}
+
+#[derive(Clone, Serialize, PartialEq, Deserialize, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub enum PlayerErrorKind {
+    Game(#[schemars(with = "GameErrorDef")] GameError),
+    InvalidName(StaticStr),
+    NameTaken,
+    NotOwner,
+    UnknownUser(UserId),
+}
+
+#[derive(Clone, Serialize, PartialEq, Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct RoomMessage {
     // if from_user_id is none, the message comes from system
@@ -86,6 +294,10 @@ pub struct RoomMessage {
     pub from_user_id: Option<UserId>,
     #[serde(skip_deserializing)]
     pub to_user_id: Option<UserId>,
+    // set when the message leaves the room: public events increment it,
+    // private messages carry the sequence number of the last event
+    #[serde(skip_deserializing)]
+    pub seq: Option<u64>,
     pub msg_type: RoomMessageType,
 }
 pub type CardEmoji = ArrayString<typenum::U4>;
@@ -96,30 +308,138 @@ pub type Rooms = Arc<DashMap<Uuid, Arc<RwLock<Room>>>>;
 pub struct Room {
     pub id: Uuid,
     pub state: RoomState,
+    pub settings: RoomSettings,
+    // rooms created before owners existed have none, anyone can administer them
+    pub owner: Option<UserId>,
     pub viewers: HashSet<UserId>,
     pub bots: [Option<UserId>; PLAYER_NUMBER],
     #[serde(skip_serializing)]
-    pub sender: Option<Sender<RoomMessage>>,
+    pub rematch_votes: HashSet<UserId>,
+    #[serde(skip_serializing)]
+    pub record: GameRecord,
+    // open sockets by user, a user can have more than one tab open
     #[serde(skip_serializing)]
-    pub receiver: InactiveReceiver<RoomMessage>,
+    pub connections: HashMap<UserId, usize>,
+    #[serde(skip_serializing)]
+    pub disconnected_since: HashMap<UserId, Instant>,
+    // none while someone is connected
+    #[serde(skip_serializing)]
+    pub idle_since: Option<Instant>,
+    #[serde(skip_serializing)]
+    pub outbox: Outbox,
+    #[serde(skip_serializing)]
+    pub lobby: Lobby,
     #[serde(skip_serializing)]
     pub task: Option<JoinHandle<Result<(), Box<dyn Error + Send + Sync>>>>,
     #[serde(skip_serializing)]
     pub pool: Pool<Sqlite>,
 }
 
+// what the lobby shows of a room
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct RoomSummary {
+    pub id: Uuid,
+    pub status: RoomStatus,
+    pub seats: [Option<User>; PLAYER_NUMBER],
+    pub viewers: usize,
+    pub settings: RoomSettings,
+}
+
+#[derive(Serialize, Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum RoomStatus {
+    Waiting,
+    Playing,
+    Done,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum LobbyUpdate {
+    Room(RoomSummary),
+    Removed(Uuid),
+    // the page fell behind and starts over with these rooms
+    Reset(Vec<RoomSummary>),
+}
+
+// the rooms as last published to the lobby, and the feed of their changes
+#[derive(Clone)]
+pub struct Lobby {
+    pub rooms: Arc<Mutex<HashMap<Uuid, RoomSummary>>>,
+    pub sender: Sender<LobbyUpdate>,
+    pub receiver: InactiveReceiver<LobbyUpdate>,
+}
+
+// routes the messages of a room. sockets write to the room task through
+// the room channel, public events are broadcast to every socket and private
+// messages only reach the sockets of their recipient.
+#[derive(Clone)]
+pub struct Outbox {
+    // the room task and the timeout bots also listen to the public events
+    pub room_sender: Sender<RoomMessage>,
+    pub room_receiver: InactiveReceiver<RoomMessage>,
+    pub public_sender: Sender<RoomMessage>,
+    pub public_receiver: InactiveReceiver<RoomMessage>,
+    pub user_senders: Arc<Mutex<HashMap<UserId, Vec<mpsc::Sender<RoomMessage>>>>>,
+    pub event_log: Arc<Mutex<EventLog>>,
+}
+
+// the games played in a room
+pub struct GameRecord {
+    // none until the first game starts
+    pub game_id: Option<Uuid>,
+    // scores of the finished games
+    pub session: SessionScores,
+    // tricks of the current hand, lost on restart
+    pub tricks: Vec<Trick>,
+    // written to game_events in order by a task of their own
+    pub events: mpsc::UnboundedSender<GameEventRow>,
+}
+
+// the last public events of a room
+#[derive(Default)]
+pub struct EventLog {
+    pub seq: u64,
+    pub events: VecDeque<RoomMessage>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum RoomState {
-    WaitingForPlayers([Option<UserId>; PLAYER_NUMBER]),
+    WaitingForPlayers([Option<User>; PLAYER_NUMBER]),
     Started([User; PLAYER_NUMBER], Game),
     Done([User; PLAYER_NUMBER], Game),
 }
 
+// chosen when creating the room
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RoomSettings {
+    pub hands: u8,
+    pub timeout_secs: u64,
+    pub bot_sleep_secs: u64,
+    pub compute_score_delay_secs: u64,
+    pub end_condition: EndCondition,
+    pub score_threshold: usize,
+}
+
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum EndCondition {
+    // the game ends after `hands` hands
+    FixedHands,
+    // the game ends at the end of the hand where someone reaches `score_threshold`
+    ScoreThreshold,
+}
+
 pub struct DbRoom {
     pub id: Uuid,
     pub state: RoomState,
+    pub settings: RoomSettings,
+    pub owner: Option<UserId>,
     pub bots: [Option<UserId>; lib_hearts::PLAYER_NUMBER],
     pub viewers: HashSet<UserId>,
+    pub game_id: Option<Uuid>,
+    pub session: SessionScores,
 }
 
 #[derive(Debug)]
@@ -139,10 +459,306 @@ impl RoomError {
 }
 pub type UserId = Uuid;
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize, Hash, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, Hash, PartialEq, Eq, JsonSchema)]
 pub struct User {
     pub id: UserId,
     pub is_guest: bool,
+    #[schemars(with = "String")]
     pub name: ArrayString<typenum::U12>,
     pub bot: bool,
 }
+
+// shapes and encodings of the room messages, by protocol version.
+// old clients keep getting the shapes they know while a deploy rolls out.
+pub mod wire {
+    use std::error::Error;
+
+    use lib_hearts::{GameError, PLAYER_CARD_SIZE, PLAYER_NUMBER};
+    use serde_derive::Serialize;
+
+    use super::{PlayerCard, PlayerErrorKind, RoomMessage, RoomMessageType, UserId};
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum ProtocolVersion {
+        // before users had names
+        V1,
+        V2,
+    }
+
+    impl ProtocolVersion {
+        pub const ALL: [ProtocolVersion; 2] = [ProtocolVersion::V1, ProtocolVersion::V2];
+        // clients that don't ask for a version were written before versions existed
+        pub const DEFAULT: ProtocolVersion = ProtocolVersion::V1;
+
+        pub fn name(self) -> &'static str {
+            match self {
+                ProtocolVersion::V1 => "hearts.v1",
+                ProtocolVersion::V2 => "hearts.v2",
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<ProtocolVersion> {
+            ProtocolVersion::ALL.into_iter().find(|v| v.name() == name)
+        }
+    }
+
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub enum Encoding {
+        Json,
+        MessagePack,
+    }
+
+    impl Encoding {
+        pub const ALL: [Encoding; 2] = [Encoding::Json, Encoding::MessagePack];
+
+        fn suffix(self) -> Option<&'static str> {
+            match self {
+                Encoding::Json => None,
+                Encoding::MessagePack => Some("msgpack"),
+            }
+        }
+    }
+
+    // what a connection speaks, e.g `hearts.v2+msgpack`
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    pub struct Protocol {
+        pub version: ProtocolVersion,
+        pub encoding: Encoding,
+    }
+
+    impl Protocol {
+        pub const DEFAULT: Protocol = Protocol {
+            version: ProtocolVersion::DEFAULT,
+            encoding: Encoding::Json,
+        };
+
+        pub fn name(self) -> String {
+            match self.encoding.suffix() {
+                Some(suffix) => format!("{}+{suffix}", self.version.name()),
+                None => self.version.name().to_string(),
+            }
+        }
+
+        pub fn from_name(name: &str) -> Option<Protocol> {
+            let (version, encoding) = match name.split_once('+') {
+                Some((version, suffix)) => (
+                    version,
+                    Encoding::ALL
+                        .into_iter()
+                        .find(|e| e.suffix() == Some(suffix))?,
+                ),
+                None => (name, Encoding::Json),
+            };
+            let version = ProtocolVersion::from_name(version)?;
+            // v1 clients only speak json
+            if version == ProtocolVersion::V1 && encoding != Encoding::Json {
+                return None;
+            }
+            Some(Protocol { version, encoding })
+        }
+
+        pub fn supported() -> Vec<Protocol> {
+            ProtocolVersion::ALL
+                .into_iter()
+                .flat_map(|version| {
+                    Encoding::ALL
+                        .into_iter()
+                        .map(move |encoding| Protocol { version, encoding })
+                })
+                .filter(|p| Protocol::from_name(&p.name()).is_some())
+                .collect()
+        }
+    }
+
+    pub enum Frame {
+        Text(String),
+        Binary(Vec<u8>),
+    }
+
+    pub type WireError = Box<dyn Error + Send + Sync>;
+
+    // v1 shapes of the variants that changed since
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    enum V1MessageType<'a> {
+        Joined(UserId),
+        PlayerError(&'a GameError),
+        WaitingForPlayers([Option<UserId>; PLAYER_NUMBER]),
+        ReceiveCards(&'a [Option<PlayerCard>; PLAYER_CARD_SIZE]),
+    }
+
+    #[derive(Serialize)]
+    #[serde(untagged)]
+    enum V1Type<'a> {
+        Changed(V1MessageType<'a>),
+        Unchanged(&'a RoomMessageType),
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct V1Message<'a> {
+        from_user_id: Option<UserId>,
+        to_user_id: Option<UserId>,
+        seq: Option<u64>,
+        msg_type: V1Type<'a>,
+    }
+
+    // none if the client would not understand the message
+    pub fn to_json(
+        version: ProtocolVersion,
+        msg: &RoomMessage,
+    ) -> Option<serde_json::Result<String>> {
+        if version == ProtocolVersion::V2 {
+            return Some(serde_json::to_string(msg));
+        }
+        let msg_type = match &msg.msg_type {
+            RoomMessageType::Joined(user) => V1Type::Changed(V1MessageType::Joined(user.id)),
+            RoomMessageType::PlayerError(PlayerErrorKind::Game(e)) => {
+                V1Type::Changed(V1MessageType::PlayerError(e))
+            }
+            RoomMessageType::PlayerError(_) => return None,
+            RoomMessageType::WaitingForPlayers(seats) => V1Type::Changed(
+                V1MessageType::WaitingForPlayers(seats.map(|seat| seat.map(|u| u.id))),
+            ),
+            RoomMessageType::ReceiveCards { cards, .. } => {
+                V1Type::Changed(V1MessageType::ReceiveCards(cards))
+            }
+            msg_type => V1Type::Unchanged(msg_type),
+        };
+        Some(serde_json::to_string(&V1Message {
+            from_user_id: msg.from_user_id,
+            to_user_id: msg.to_user_id,
+            seq: msg.seq,
+            msg_type,
+        }))
+    }
+
+    // none if the client would not understand the message
+    pub fn encode(protocol: Protocol, msg: &RoomMessage) -> Option<Result<Frame, WireError>> {
+        let frame = match protocol.encoding {
+            Encoding::Json => {
+                return to_json(protocol.version, msg)
+                    .map(|json| json.map(Frame::Text).map_err(Into::into))
+            }
+            // field names are kept, so that skipped and new fields don't shift the others
+            Encoding::MessagePack => rmp_serde::to_vec_named(msg)
+                .map(Frame::Binary)
+                .map_err(Into::into),
+        };
+        Some(frame)
+    }
+
+    // none if the connection doesn't use a binary encoding
+    pub fn decode_binary(
+        encoding: Encoding,
+        bytes: &[u8],
+    ) -> Option<Result<RoomMessage, WireError>> {
+        match encoding {
+            Encoding::Json => None,
+            Encoding::MessagePack => Some(rmp_serde::from_slice(bytes).map_err(Into::into)),
+        }
+    }
+
+    #[cfg(test)]
+    mod test {
+        use lib_hearts::{GameError, PLAYER_CARD_SIZE};
+        use serde_json::json;
+
+        use super::{decode_binary, encode, to_json, Encoding, Frame, Protocol, ProtocolVersion};
+        use crate::data::{PlayerCard, PlayerErrorKind, RoomMessage, RoomMessageType, User};
+
+        fn to_value(version: ProtocolVersion, msg_type: RoomMessageType) -> serde_json::Value {
+            let msg = RoomMessage {
+                from_user_id: None,
+                to_user_id: None,
+                seq: Some(1),
+                msg_type,
+            };
+            serde_json::from_str(&to_json(version, &msg).unwrap().unwrap()).unwrap()
+        }
+
+        #[test]
+        fn test_protocol_version() {
+            assert_eq!(
+                Some(ProtocolVersion::V2),
+                ProtocolVersion::from_name("hearts.v2")
+            );
+            assert_eq!(None, ProtocolVersion::from_name("hearts.v3"));
+            assert_eq!(
+                Some(Protocol {
+                    version: ProtocolVersion::V2,
+                    encoding: Encoding::MessagePack
+                }),
+                Protocol::from_name("hearts.v2+msgpack")
+            );
+            assert_eq!(None, Protocol::from_name("hearts.v1+msgpack"));
+            assert_eq!(None, Protocol::from_name("hearts.v2+xml"));
+            assert_eq!(3, Protocol::supported().len());
+        }
+
+        #[test]
+        fn test_binary_round_trip() {
+            let msg = RoomMessage::from_user(
+                uuid::Uuid::new_v4(),
+                RoomMessageType::Play(PlayerCard {
+                    type_card: lib_hearts::TypeCard::Heart,
+                    emoji: crate::data::CardEmoji::from_str_truncate("🂱"),
+                    position_in_deck: 12,
+                }),
+            );
+            let protocol = Protocol {
+                version: ProtocolVersion::V2,
+                encoding: Encoding::MessagePack,
+            };
+            let Some(Ok(Frame::Binary(bytes))) = encode(protocol, &msg) else {
+                panic!("expected a binary frame");
+            };
+            let decoded = decode_binary(Encoding::MessagePack, &bytes)
+                .unwrap()
+                .unwrap();
+            assert_eq!(msg, decoded);
+            assert!(decode_binary(Encoding::Json, &[]).is_none());
+        }
+
+        #[test]
+        fn test_v1_shapes() {
+            let user = User::default();
+            let v1 = to_value(ProtocolVersion::V1, RoomMessageType::Joined(user));
+            assert_eq!(json!(user.id), v1["msgType"]["joined"]);
+            let v2 = to_value(ProtocolVersion::V2, RoomMessageType::Joined(user));
+            assert_eq!(json!(user.id), v2["msgType"]["joined"]["id"]);
+
+            let v1 = to_value(
+                ProtocolVersion::V1,
+                RoomMessageType::WaitingForPlayers([Some(user), None, None, None]),
+            );
+            assert_eq!(
+                json!([user.id, null, null, null]),
+                v1["msgType"]["waitingForPlayers"]
+            );
+
+            let v1 = to_value(
+                ProtocolVersion::V1,
+                RoomMessageType::PlayerError(PlayerErrorKind::Game(GameError::StateError)),
+            );
+            assert_eq!(json!(GameError::StateError), v1["msgType"]["playerError"]);
+            let msg = RoomMessage::public(RoomMessageType::PlayerError(PlayerErrorKind::NameTaken));
+            assert!(to_json(ProtocolVersion::V1, &msg).is_none());
+
+            let v1 = to_value(
+                ProtocolVersion::V1,
+                RoomMessageType::ReceiveCards {
+                    cards: [None; PLAYER_CARD_SIZE],
+                    legal_moves: Some(vec![]),
+                },
+            );
+            assert_eq!(
+                Some(PLAYER_CARD_SIZE),
+                v1["msgType"]["receiveCards"].as_array().map(Vec::len)
+            );
+
+            let v1 = to_value(ProtocolVersion::V1, RoomMessageType::GetCurrentState);
+            assert_eq!(json!("getCurrentState"), v1["msgType"]);
+        }
+    }
+}
@@ -4,7 +4,9 @@ use arraystring::ArrayString;
 use sqlx::{Pool, Sqlite};
 use uuid::Uuid;
 
-use crate::data::{DbRoom, Room, User};
+use crate::data::{
+    DbRoom, GameEvent, GameEventRow, Rating, Room, RoomSettings, User, UserId, UserStats,
+};
 
 pub async fn find_user_by_id(id: Uuid, pool: &Pool<Sqlite>) -> Result<User, Box<dyn Error>> {
     let row = sqlx::query!("select id, name, is_guest from users where id = ?", id)
@@ -33,6 +35,48 @@ pub async fn upsert_user(user: User, pool: &Pool<Sqlite>) -> Result<(), Box<dyn
     Ok(())
 }
 
+pub async fn find_credentials_by_name(
+    name: &str,
+    pool: &Pool<Sqlite>,
+) -> Result<Option<(UserId, String)>, Box<dyn Error>> {
+    let row = sqlx::query!(
+        "select id, password_hash from users where name = ? and is_guest = 0",
+        name
+    )
+    .fetch_optional(pool)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let password_hash = row
+        .password_hash
+        .ok_or("registered user without password")?;
+    Ok(Some((Uuid::from_slice(&row.id[..])?, password_hash)))
+}
+
+// turns a guest into a registered user, keeping its id
+pub async fn register_user(
+    user: User,
+    password_hash: &str,
+    pool: &Pool<Sqlite>,
+) -> Result<(), Box<dyn Error>> {
+    let mut conn = pool.acquire().await?;
+    let name = user.name.to_string();
+    let id = user.id;
+    let _ = sqlx::query!(
+        r#"
+        UPDATE users SET name = ?2, is_guest = 0, password_hash = ?3
+        WHERE id = ?1;
+    "#,
+        id,
+        name,
+        password_hash
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
 fn row_to_user(id: Vec<u8>, name: String, is_guest: bool) -> Result<User, Box<dyn Error>> {
     Ok(User {
         id: Uuid::from_slice(&id[..])?,
@@ -43,9 +87,12 @@ fn row_to_user(id: Vec<u8>, name: String, is_guest: bool) -> Result<User, Box<dy
 }
 
 pub async fn find_room_by_id(id: Uuid, pool: &Pool<Sqlite>) -> Result<DbRoom, Box<dyn Error>> {
-    let row = sqlx::query!("select id, state, bots from rooms where id = ?", id)
-        .fetch_one(pool)
-        .await?;
+    let row = sqlx::query!(
+        "select id, state, bots, settings, owner, game_id, session from rooms where id = ?",
+        id
+    )
+    .fetch_one(pool)
+    .await?;
     let id = Uuid::from_slice(&row.id[..])?;
 
     let row_viewers = sqlx::query!("select user_id from room_viewers where room_id = ?", id)
@@ -56,48 +103,82 @@ pub async fn find_room_by_id(id: Uuid, pool: &Pool<Sqlite>) -> Result<DbRoom, Bo
         id,
         row.state,
         row.bots,
+        row.settings,
+        row.owner,
+        row.game_id,
+        row.session,
         row_viewers.into_iter().map(|v| v.user_id).collect(),
     )
 }
 
-pub async fn find_all_rooms(id: Uuid, pool: &Pool<Sqlite>) -> Result<Vec<DbRoom>, Box<dyn Error>> {
-    let rows = sqlx::query!("select id, state, bots from rooms")
+pub async fn find_all_rooms(pool: &Pool<Sqlite>) -> Result<Vec<DbRoom>, Box<dyn Error>> {
+    let rows = sqlx::query!("select id, state, bots, settings, owner, game_id, session from rooms")
         .fetch_all(pool)
         .await?;
     let mut rooms = vec![];
 
     for row in rows {
-        let id = Uuid::from_slice(&row.id[..])?;
+        let Ok(id) = Uuid::from_slice(&row.id[..]) else {
+            tracing::error!("skip room with invalid id {:?}", row.id);
+            continue;
+        };
 
         let row_viewers = sqlx::query!("select user_id from room_viewers where room_id = ?", id)
             .fetch_all(pool)
             .await?;
 
-        let room = row_to_db_room(
+        // one broken room must not keep the others from being restored
+        match row_to_db_room(
             id,
             row.state,
             row.bots,
+            row.settings,
+            row.owner,
+            row.game_id,
+            row.session,
             row_viewers.into_iter().map(|v| v.user_id).collect(),
-        )?;
-        rooms.push(room);
+        ) {
+            Ok(room) => rooms.push(room),
+            Err(e) => tracing::error!("skip room {id}, could not read it: {e}"),
+        }
     }
     Ok(rooms)
 }
 
+#[allow(clippy::too_many_arguments)]
 fn row_to_db_room(
     id: Uuid,
     state: String,
     bots: String,
+    settings: Option<String>,
+    owner: Option<Vec<u8>>,
+    game_id: Option<Vec<u8>>,
+    session: Option<String>,
     viewers: Vec<Vec<u8>>,
 ) -> Result<DbRoom, Box<dyn Error>> {
     Ok(DbRoom {
         id,
         state: serde_json::from_str(&state)?,
         bots: serde_json::from_str(&bots)?,
+        // rooms created before settings existed use the defaults
+        settings: match settings {
+            Some(settings) => serde_json::from_str(&settings)?,
+            None => RoomSettings::default(),
+        },
+        owner: owner
+            .map(|owner| Uuid::from_slice(&owner[..]))
+            .transpose()?,
         viewers: viewers
             .into_iter()
             .map(|v| Uuid::from_slice(&v[..]))
             .collect::<Result<HashSet<_>, _>>()?,
+        game_id: game_id
+            .map(|game_id| Uuid::from_slice(&game_id[..]))
+            .transpose()?,
+        session: session
+            .map(|session| serde_json::from_str(&session))
+            .transpose()?
+            .unwrap_or_default(),
     })
 }
 
@@ -105,16 +186,25 @@ pub async fn upsert_room(room: &Room, pool: &Pool<Sqlite>) -> Result<(), Box<dyn
     let mut conn = pool.acquire().await?;
     let state = serde_json::to_string(&room.state)?;
     let bots = serde_json::to_string(&room.bots)?;
+    let settings = serde_json::to_string(&room.settings)?;
     let id = room.id;
+    let owner = room.owner;
+    let game_id = room.record.game_id;
+    let session = serde_json::to_string(&room.record.session)?;
     let _ = sqlx::query!(
         r#"
-        INSERT into rooms(id, state, bots)
-        VALUES (?1, ?2, ?3)
-        ON CONFLICT DO UPDATE SET state=?2, bots=?3;
+        INSERT into rooms(id, state, bots, settings, owner, game_id, session)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)
+        ON CONFLICT DO UPDATE SET state=?2, bots=?3, settings=?4, owner=?5, game_id=?6,
+            session=?7;
     "#,
         id,
         state,
-        bots
+        bots,
+        settings,
+        owner,
+        game_id,
+        session
     )
     .execute(&mut *conn)
     .await?;
@@ -133,3 +223,537 @@ pub async fn upsert_room(room: &Room, pool: &Pool<Sqlite>) -> Result<(), Box<dyn
 
     Ok(())
 }
+
+pub async fn delete_room_viewer(
+    room_id: Uuid,
+    user_id: UserId,
+    pool: &Pool<Sqlite>,
+) -> Result<(), Box<dyn Error>> {
+    let _ = sqlx::query!(
+        "delete from room_viewers where room_id = ? and user_id = ?",
+        room_id,
+        user_id
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// moves the room to archived_rooms, so it isn't restored anymore
+pub async fn archive_room(
+    id: Uuid,
+    archived_at: i64,
+    pool: &Pool<Sqlite>,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    let _ = sqlx::query!(
+        r#"
+        INSERT OR REPLACE into archived_rooms
+            (id, state, bots, settings, owner, game_id, session, archived_at)
+        SELECT id, state, bots, settings, owner, game_id, session, ?2 from rooms where id = ?1;
+    "#,
+        id,
+        archived_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    let _ = sqlx::query!("delete from room_viewers where room_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    let _ = sqlx::query!("delete from rooms where id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn delete_room(id: Uuid, pool: &Pool<Sqlite>) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    let _ = sqlx::query!("delete from room_viewers where room_id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    let _ = sqlx::query!("delete from rooms where id = ?", id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+// the games table keeps who played and when, the events keep how
+pub async fn insert_game_event(
+    row: &GameEventRow,
+    pool: &Pool<Sqlite>,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    let game_id = row.game_id;
+    let created_at = row.created_at;
+    match &row.event {
+        GameEvent::Started {
+            room_id,
+            players,
+            settings,
+        } => {
+            let players = serde_json::to_string(players)?;
+            let settings = serde_json::to_string(settings)?;
+            let _ = sqlx::query!(
+                r#"
+                INSERT into games (id, room_id, players, settings, started_at)
+                VALUES (?1, ?2, ?3, ?4, ?5);
+            "#,
+                game_id,
+                room_id,
+                players,
+                settings,
+                created_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        GameEvent::Ended { .. } => {
+            let _ = sqlx::query!(
+                "UPDATE games SET ended_at = ?2 WHERE id = ?1;",
+                game_id,
+                created_at
+            )
+            .execute(&mut *tx)
+            .await?;
+        }
+        _ => {}
+    }
+    let user_id = row.user_id;
+    let kind = row.event.kind();
+    let event = serde_json::to_string(&row.event)?;
+    let _ = sqlx::query!(
+        r#"
+        INSERT into game_events (game_id, user_id, kind, event, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5);
+    "#,
+        game_id,
+        user_id,
+        kind,
+        event,
+        created_at
+    )
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn find_game_events(
+    game_id: Uuid,
+    pool: &Pool<Sqlite>,
+) -> Result<Vec<GameEventRow>, Box<dyn Error>> {
+    let rows = sqlx::query!(
+        "select user_id, event, created_at from game_events where game_id = ? order by id",
+        game_id
+    )
+    .fetch_all(pool)
+    .await?;
+    rows.into_iter()
+        .map(|row| {
+            Ok(GameEventRow {
+                game_id,
+                user_id: row
+                    .user_id
+                    .map(|user_id| Uuid::from_slice(&user_id[..]))
+                    .transpose()?,
+                event: serde_json::from_str(&row.event)?,
+                created_at: row.created_at,
+            })
+        })
+        .collect()
+}
+
+pub async fn add_user_stats(
+    user_id: UserId,
+    stats: UserStats,
+    pool: &Pool<Sqlite>,
+) -> Result<(), Box<dyn Error>> {
+    let _ = sqlx::query!(
+        r#"
+        INSERT into user_stats (user_id, games_played, wins, total_points, moons_shot, queens_taken)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+        ON CONFLICT DO UPDATE SET games_played = games_played + ?2, wins = wins + ?3,
+            total_points = total_points + ?4, moons_shot = moons_shot + ?5,
+            queens_taken = queens_taken + ?6;
+    "#,
+        user_id,
+        stats.games_played,
+        stats.wins,
+        stats.total_points,
+        stats.moons_shot,
+        stats.queens_taken
+    )
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+// users who never finished a game have no row yet
+pub async fn find_user_stats(
+    user_id: UserId,
+    pool: &Pool<Sqlite>,
+) -> Result<UserStats, Box<dyn Error>> {
+    let row = sqlx::query_as!(
+        UserStats,
+        r#"
+        select games_played, wins, total_points, moons_shot, queens_taken
+        from user_stats where user_id = ?
+    "#,
+        user_id
+    )
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.unwrap_or_default())
+}
+
+pub async fn find_rating(
+    user_id: UserId,
+    pool: &Pool<Sqlite>,
+) -> Result<Option<f64>, Box<dyn Error>> {
+    let rating = sqlx::query_scalar!("select rating from ratings where user_id = ?", user_id)
+        .fetch_optional(pool)
+        .await?;
+    Ok(rating)
+}
+
+// the new ratings of the rated players of a game, and their history
+pub async fn update_ratings(
+    game_id: Uuid,
+    ratings: &[(UserId, f64)],
+    created_at: i64,
+    pool: &Pool<Sqlite>,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = pool.begin().await?;
+    for (user_id, rating) in ratings {
+        let _ = sqlx::query!(
+            r#"
+            INSERT into ratings (user_id, rating, games_played)
+            VALUES (?1, ?2, 1)
+            ON CONFLICT DO UPDATE SET rating = ?2, games_played = games_played + 1;
+        "#,
+            user_id,
+            rating
+        )
+        .execute(&mut *tx)
+        .await?;
+        let _ = sqlx::query!(
+            r#"
+            INSERT into rating_history (user_id, game_id, rating, created_at)
+            VALUES (?1, ?2, ?3, ?4);
+        "#,
+            user_id,
+            game_id,
+            rating,
+            created_at
+        )
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+pub async fn find_leaderboard(
+    limit: i64,
+    history_size: i64,
+    pool: &Pool<Sqlite>,
+) -> Result<Vec<Rating>, Box<dyn Error>> {
+    let rows = sqlx::query!(
+        r#"
+        select u.id, u.name, u.is_guest, r.rating, r.games_played
+        from ratings r join users u on u.id = r.user_id
+        where u.is_guest = 0
+        order by r.rating desc limit ?
+    "#,
+        limit
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut leaderboard = Vec::with_capacity(rows.len());
+    for row in rows {
+        let user = row_to_user(row.id, row.name, row.is_guest)?;
+        let mut history = sqlx::query_scalar!(
+            r#"
+            select rating from rating_history where user_id = ?
+            order by id desc limit ?
+        "#,
+            user.id,
+            history_size
+        )
+        .fetch_all(pool)
+        .await?;
+        history.reverse();
+        leaderboard.push(Rating {
+            user,
+            rating: row.rating,
+            games_played: row.games_played,
+            history,
+        });
+    }
+    Ok(leaderboard)
+}
+
+// in memory database with the whole schema, for the tests
+#[cfg(test)]
+pub async fn test_pool() -> Pool<Sqlite> {
+    // in memory databases are per connection
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    sqlx::migrate!().run(&pool).await.unwrap();
+    pool
+}
+
+// players of a game by their (is_guest, bot) flags, for the tests
+#[cfg(test)]
+pub fn test_players(
+    flags: [(bool, bool); lib_hearts::PLAYER_NUMBER],
+) -> [User; lib_hearts::PLAYER_NUMBER] {
+    flags.map(|(is_guest, bot)| User {
+        id: Uuid::new_v4(),
+        is_guest,
+        bot,
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+pub fn test_event_row(game_id: Uuid, user_id: Option<UserId>, event: GameEvent) -> GameEventRow {
+    GameEventRow {
+        game_id,
+        user_id,
+        event,
+        created_at: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use lib_hearts::{PlayerState, PLAYER_NUMBER};
+    use uuid::Uuid;
+
+    use super::{
+        add_user_stats, archive_room, find_all_rooms, find_game_events, find_leaderboard,
+        find_rating, find_user_stats, insert_game_event, test_event_row, test_players, test_pool,
+        update_ratings, upsert_user,
+    };
+    use crate::data::{GameEvent, GameEventRow, RoomSettings, RoomState, User, UserStats};
+
+    #[tokio::test]
+    async fn test_game_events() {
+        let pool = test_pool().await;
+
+        let game_id = Uuid::new_v4();
+        let players = test_players([(false, false); PLAYER_NUMBER]);
+        let player_scores = players.map(|p| PlayerState {
+            player_id: p.id,
+            score: 0,
+        });
+        let rows = [
+            GameEvent::Started {
+                room_id: Uuid::new_v4(),
+                players,
+                settings: RoomSettings::default(),
+            },
+            GameEvent::Ended {
+                player_scores,
+                winners: players.iter().map(|p| p.id).collect(),
+            },
+        ]
+        .map(|event| GameEventRow {
+            created_at: 42,
+            ..test_event_row(game_id, None, event)
+        });
+        for row in &rows {
+            insert_game_event(row, &pool).await.unwrap();
+        }
+
+        assert_eq!(
+            rows.to_vec(),
+            find_game_events(game_id, &pool).await.unwrap()
+        );
+        let ended_at: Option<i64> = sqlx::query_scalar("select ended_at from games where id = ?")
+            .bind(game_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(Some(42), ended_at);
+    }
+
+    #[tokio::test]
+    async fn test_user_stats() {
+        let pool = test_pool().await;
+        let user = User {
+            id: Uuid::new_v4(),
+            ..Default::default()
+        };
+        upsert_user(user, &pool).await.unwrap();
+        assert_eq!(
+            UserStats::default(),
+            find_user_stats(user.id, &pool).await.unwrap()
+        );
+
+        let stats = UserStats {
+            games_played: 1,
+            wins: 1,
+            total_points: 12,
+            moons_shot: 1,
+            queens_taken: 2,
+        };
+        add_user_stats(user.id, stats, &pool).await.unwrap();
+        add_user_stats(user.id, stats, &pool).await.unwrap();
+        assert_eq!(
+            UserStats {
+                games_played: 2,
+                wins: 2,
+                total_points: 24,
+                moons_shot: 2,
+                queens_taken: 4,
+            },
+            find_user_stats(user.id, &pool).await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ratings() {
+        let pool = test_pool().await;
+        let [registered, guest] = [false, true].map(|is_guest| User {
+            id: Uuid::new_v4(),
+            is_guest,
+            bot: false,
+            ..Default::default()
+        });
+        for user in [registered, guest] {
+            upsert_user(user, &pool).await.unwrap();
+        }
+        assert_eq!(None, find_rating(registered.id, &pool).await.unwrap());
+
+        // the history points to a played game
+        let ratings = [(registered.id, 1500.), (guest.id, 1500.)];
+        assert!(update_ratings(Uuid::new_v4(), &ratings, 0, &pool)
+            .await
+            .is_err());
+
+        for rating in [1510., 1490., 1520.] {
+            let game_id = Uuid::new_v4();
+            let started = test_event_row(
+                game_id,
+                None,
+                GameEvent::Started {
+                    room_id: Uuid::new_v4(),
+                    players: [registered, guest, User::default(), User::default()],
+                    settings: RoomSettings::default(),
+                },
+            );
+            insert_game_event(&started, &pool).await.unwrap();
+            update_ratings(
+                game_id,
+                &[(registered.id, rating), (guest.id, rating)],
+                0,
+                &pool,
+            )
+            .await
+            .unwrap();
+        }
+        assert_eq!(
+            Some(1520.),
+            find_rating(registered.id, &pool).await.unwrap()
+        );
+
+        // guests are left out, history is the latest ratings oldest first
+        let leaderboard = find_leaderboard(10, 2, &pool).await.unwrap();
+        assert_eq!(1, leaderboard.len());
+        assert_eq!(registered, leaderboard[0].user);
+        assert_eq!(3, leaderboard[0].games_played);
+        assert_eq!(vec![1490., 1520.], leaderboard[0].history);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_waiting_room() {
+        let pool = test_pool().await;
+        let user = User {
+            id: Uuid::new_v4(),
+            bot: false,
+            ..Default::default()
+        };
+        upsert_user(user, &pool).await.unwrap();
+        let (bot_id, unknown_id) = (Uuid::new_v4(), Uuid::new_v4());
+        // rooms saved before the seats had names
+        sqlx::query("insert into rooms (id, state, bots) values (?, ?, ?)")
+            .bind(Uuid::new_v4())
+            .bind(format!(
+                r#"{{"WaitingForPlayers":["{}",null,"{bot_id}","{unknown_id}"]}}"#,
+                user.id
+            ))
+            .bind(format!(r#"[null,null,"{bot_id}",null]"#))
+            .execute(&pool)
+            .await
+            .unwrap();
+        sqlx::query(include_str!(
+            "../migrations/20261018170000_waiting_room_users.sql"
+        ))
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let rooms = find_all_rooms(&pool).await.unwrap();
+        let RoomState::WaitingForPlayers([Some(seat), None, Some(bot), Some(unknown)]) =
+            rooms[0].state
+        else {
+            panic!("seats not migrated");
+        };
+        assert_eq!(user, seat);
+        assert_eq!((bot_id, true), (bot.id, bot.bot));
+        assert_eq!((unknown_id, false), (unknown.id, unknown.bot));
+    }
+
+    #[tokio::test]
+    async fn test_find_all_rooms_skips_broken_rooms() {
+        let pool = test_pool().await;
+        let seats = "[null,null,null,null]";
+        for (state, bots) in [
+            (format!(r#"{{"WaitingForPlayers":{seats}}}"#), seats),
+            (String::from("state"), seats),
+            (format!(r#"{{"WaitingForPlayers":{seats}}}"#), "bots"),
+        ] {
+            sqlx::query("insert into rooms (id, state, bots) values (?, ?, ?)")
+                .bind(Uuid::new_v4())
+                .bind(state)
+                .bind(bots)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(1, find_all_rooms(&pool).await.unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn test_archive_room() {
+        let pool = test_pool().await;
+        let id = Uuid::new_v4();
+        sqlx::query("insert into rooms (id, state, bots) values (?, 'state', 'bots')")
+            .bind(id)
+            .execute(&pool)
+            .await
+            .unwrap();
+
+        archive_room(id, 42, &pool).await.unwrap();
+        let rooms: i64 = sqlx::query_scalar("select count(*) from rooms")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(0, rooms);
+        let archived: (String, i64) =
+            sqlx::query_as("select state, archived_at from archived_rooms where id = ?")
+                .bind(id)
+                .fetch_one(&pool)
+                .await
+                .unwrap();
+        assert_eq!((String::from("state"), 42), archived);
+    }
+}
@@ -2,23 +2,40 @@
 mod constants;
 mod data;
 mod db;
+mod rating;
+mod replay;
 mod room;
 mod router;
+mod schema;
+mod session;
+mod stats;
 mod templ;
 mod user;
 mod utils;
 mod websocket;
-use std::{env::var, error::Error, net::SocketAddr, str::FromStr, sync::Arc};
+use std::{env::var, error::Error, net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
-use async_session::MemoryStore;
-use constants::{SERVICE_APPLICATION_NAME, SERVICE_HOST, SERVICE_PORT};
+use constants::{
+    DEFAULT_ROOM_IDLE_SECS, ROOM_IDLE_SECS, ROOM_REAPER_INTERVAL_SECS, SERVICE_APPLICATION_NAME,
+    SERVICE_HOST, SERVICE_PORT,
+};
 use dashmap::DashMap;
 use router::{get_router, setup_tracing};
+use session::SqliteSessionStore;
 use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
 
-use crate::constants::{SQLITE_DB_URL, WS_ENDPOINT};
+use crate::{
+    constants::{SESSION_CLEANUP_INTERVAL_SECS, SQLITE_DB_URL, WS_ENDPOINT},
+    data::{Lobby, Room},
+    db::find_all_rooms,
+    room::spawn_reaper,
+};
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
+    // `hearts-ws schema` writes the json schema and typescript definitions of the messages
+    if std::env::args().nth(1).as_deref() == Some("schema") {
+        return schema::write_files();
+    }
     setup_tracing()?;
 
     let host = var(SERVICE_HOST).unwrap_or_else(|_| String::from("0.0.0.0"));
@@ -26,15 +43,39 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let ws_endpoint = var(WS_ENDPOINT).unwrap_or_else(|_| String::from("ws://localhost:8080/ws"));
     let port = var(SERVICE_PORT).unwrap_or_else(|_| String::from("8080"));
     let app_name = var(SERVICE_APPLICATION_NAME).unwrap_or_else(|_| String::from("heartz"));
+    let room_idle_secs = var(ROOM_IDLE_SECS)
+        .ok()
+        .and_then(|secs| secs.parse().ok())
+        .unwrap_or(DEFAULT_ROOM_IDLE_SECS);
     let addr = SocketAddr::from_str(&format!("{host}:{port}"))?;
     let rooms = Arc::new(DashMap::with_capacity(100));
+    let lobby = Lobby::new();
     let db_pool = SqlitePoolOptions::new()
         .max_connections(5)
         .connect_with(SqliteConnectOptions::from_str(&sqlite_url)?.create_if_missing(true))
         .await?;
 
-    let store = MemoryStore::new();
-    let app = get_router(std::borrow::Cow::Owned(ws_endpoint), db_pool, rooms, store);
+    for db_room in find_all_rooms(&db_pool).await? {
+        let (id, room) =
+            Room::from_db(db_room, db_pool.clone(), rooms.clone(), lobby.clone()).await;
+        rooms.insert(id, room);
+    }
+    tracing::info!("{app_name} :: restored {} room(s)", rooms.len());
+
+    let store = SqliteSessionStore::new(db_pool.clone());
+    store.spawn_cleanup_task(Duration::from_secs(SESSION_CLEANUP_INTERVAL_SECS));
+    spawn_reaper(
+        rooms.clone(),
+        Duration::from_secs(room_idle_secs),
+        Duration::from_secs(ROOM_REAPER_INTERVAL_SECS),
+    );
+    let app = get_router(
+        std::borrow::Cow::Owned(ws_endpoint),
+        db_pool,
+        rooms,
+        lobby,
+        store,
+    );
 
     tracing::info!("{app_name} :: listening on {:?}", addr);
     axum::Server::bind(&addr)
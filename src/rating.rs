@@ -0,0 +1,140 @@
+use std::error::Error;
+
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+
+use crate::{
+    constants::{DEFAULT_RATING, RATING_K_FACTOR},
+    data::{GameEvent, GameEventRow, UserId},
+    db::{find_game_events, find_rating, update_ratings},
+};
+
+// registered players of a finished game with their final score.
+// guests and bots are not rated
+pub fn rated_players(events: &[GameEventRow]) -> Option<Vec<(UserId, usize)>> {
+    let GameEvent::Started { players, .. } = events.first()?.event else {
+        return None;
+    };
+    let GameEvent::Ended { player_scores, .. } = &events.last()?.event else {
+        return None;
+    };
+    Some(
+        player_scores
+            .iter()
+            .filter(|p| {
+                players
+                    .iter()
+                    .any(|u| u.id == p.player_id && !u.bot && !u.is_guest)
+            })
+            .map(|p| (p.player_id, p.score))
+            .collect(),
+    )
+}
+
+// elo over every pair of players, the lowest score wins the pair
+pub fn rating_updates(players: &[(f64, usize)]) -> Vec<f64> {
+    if players.len() < 2 {
+        return players.iter().map(|(rating, _)| *rating).collect();
+    }
+    let k = RATING_K_FACTOR / (players.len() - 1) as f64;
+    players
+        .iter()
+        .map(|(rating, score)| {
+            let delta: f64 = players
+                .iter()
+                .map(|(other_rating, other_score)| {
+                    let actual = match score.cmp(other_score) {
+                        std::cmp::Ordering::Less => 1.,
+                        std::cmp::Ordering::Equal => 0.5,
+                        std::cmp::Ordering::Greater => 0.,
+                    };
+                    let expected = 1. / (1. + 10f64.powf((other_rating - rating) / 400.));
+                    actual - expected
+                })
+                .sum();
+            // comparing a player with itself adds 0.5 - 0.5
+            rating + k * delta
+        })
+        .collect()
+}
+
+pub async fn record_game_ratings(game_id: Uuid, pool: &Pool<Sqlite>) -> Result<(), Box<dyn Error>> {
+    let events = find_game_events(game_id, pool).await?;
+    let players = rated_players(&events).ok_or("game is not over")?;
+    if players.len() < 2 {
+        return Ok(());
+    }
+    let mut current = Vec::with_capacity(players.len());
+    for (user_id, score) in &players {
+        let rating = find_rating(*user_id, pool).await?.unwrap_or(DEFAULT_RATING);
+        current.push((rating, *score));
+    }
+    let ratings: Vec<_> = players
+        .iter()
+        .zip(rating_updates(&current))
+        .map(|((user_id, _), rating)| (*user_id, rating))
+        .collect();
+    update_ratings(game_id, &ratings, Utc::now().timestamp(), pool).await
+}
+
+#[cfg(test)]
+mod test {
+    use lib_hearts::PlayerState;
+    use uuid::Uuid;
+
+    use super::{rated_players, rating_updates};
+    use crate::{
+        constants::DEFAULT_RATING,
+        data::{GameEvent, RoomSettings},
+        db::{test_event_row, test_players},
+    };
+
+    #[test]
+    fn test_rating_updates() {
+        let ratings = rating_updates(&[
+            (DEFAULT_RATING, 10),
+            (DEFAULT_RATING, 20),
+            (DEFAULT_RATING, 20),
+            (DEFAULT_RATING, 30),
+        ]);
+        assert_eq!(DEFAULT_RATING + 16., ratings[0]);
+        assert_eq!(DEFAULT_RATING, ratings[1]);
+        assert_eq!(DEFAULT_RATING, ratings[2]);
+        assert_eq!(DEFAULT_RATING - 16., ratings[3]);
+
+        // beating a stronger player is worth more
+        let ratings = rating_updates(&[(1400., 0), (1600., 26)]);
+        assert!(ratings[0] - 1400. > 16.);
+        assert!((ratings[0] - 1400. + ratings[1] - 1600.).abs() < 1e-9);
+
+        assert_eq!(vec![1400.], rating_updates(&[(1400., 0)]));
+    }
+
+    #[test]
+    fn test_rated_players() {
+        let game_id = Uuid::new_v4();
+        let players = test_players([(false, false), (true, false), (false, true), (false, false)]);
+        let player_scores = players.map(|p| PlayerState {
+            player_id: p.id,
+            score: 3,
+        });
+        let events = [
+            GameEvent::Started {
+                room_id: Uuid::new_v4(),
+                players,
+                settings: RoomSettings::default(),
+            },
+            GameEvent::Ended {
+                player_scores,
+                winners: vec![],
+            },
+        ]
+        .map(|event| test_event_row(game_id, None, event));
+        assert!(rated_players(&events[..1]).is_none());
+        assert_eq!(
+            Some(vec![(players[0].id, 3), (players[3].id, 3)]),
+            rated_players(&events)
+        );
+    }
+}
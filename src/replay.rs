@@ -0,0 +1,205 @@
+use lib_hearts::{PlayerState, NUMBER_REPLACEABLE_CARDS, PLAYER_NUMBER};
+
+use crate::data::{GameEvent, GameEventRow, PlayerCard, ReplayStep, RoomMessageType, UserId};
+
+// replays the move log of a finished game, one step per event.
+// none if the log doesn't hold a whole game
+pub fn replay_steps(events: &[GameEventRow]) -> Option<Vec<ReplayStep>> {
+    let (first, rest) = events.split_first()?;
+    let GameEvent::Started { players, .. } = first.event else {
+        return None;
+    };
+    // hands are only revealed once the game is over
+    if !matches!(rest.last()?.event, GameEvent::Ended { .. }) {
+        return None;
+    }
+    let pos = |player_id: UserId| players.iter().position(|p| p.id == player_id);
+    let no_score = players.map(|p| PlayerState {
+        player_id: p.id,
+        score: 0,
+    });
+
+    let mut hand = 0;
+    let mut cards: [Vec<PlayerCard>; PLAYER_NUMBER] = Default::default();
+    let mut stack = [None; PLAYER_NUMBER];
+    let mut current_scores = no_score;
+    let mut player_scores = no_score;
+    // cards are only received once everyone exchanged
+    let mut exchanges: Vec<(usize, [PlayerCard; NUMBER_REPLACEABLE_CARDS])> = vec![];
+    // the last trick stays on the table until the next card
+    let mut trick_taken = false;
+
+    let mut steps = Vec::with_capacity(rest.len());
+    for row in rest {
+        match &row.event {
+            GameEvent::Started { .. } => return None,
+            GameEvent::Dealt {
+                hand: dealt_hand,
+                cards: dealt,
+            } => {
+                hand = *dealt_hand;
+                for dealt in dealt {
+                    cards[pos(dealt.player_id)?] = dealt.cards.clone();
+                }
+                stack = [None; PLAYER_NUMBER];
+                current_scores = no_score;
+                trick_taken = false;
+            }
+            GameEvent::Exchanged {
+                to_player_id,
+                cards: exchanged,
+            } => {
+                let from = pos(row.user_id?)?;
+                cards[from].retain(|card| !exchanged.contains(card));
+                exchanges.push((pos(*to_player_id)?, *exchanged));
+                if exchanges.len() == PLAYER_NUMBER {
+                    for (to, exchanged) in exchanges.drain(..) {
+                        cards[to].extend(exchanged);
+                        cards[to].sort_by_key(|card| card.position_in_deck);
+                    }
+                }
+            }
+            GameEvent::Played(card) => {
+                if trick_taken {
+                    stack = [None; PLAYER_NUMBER];
+                    trick_taken = false;
+                }
+                cards[pos(row.user_id?)?].retain(|c| c.position_in_deck != card.position_in_deck);
+                *stack.iter_mut().find(|slot| slot.is_none())? = Some(*card);
+            }
+            GameEvent::TrickTaken(trick) => {
+                current_scores[pos(trick.winner)?].score += trick.points;
+                trick_taken = true;
+            }
+            GameEvent::HandScored {
+                player_scores: scores,
+                ..
+            } => {
+                player_scores = *scores;
+                current_scores = no_score;
+            }
+            GameEvent::Ended {
+                player_scores: scores,
+                ..
+            } => {
+                player_scores = *scores;
+            }
+        }
+        steps.push(ReplayStep {
+            step: steps.len(),
+            steps: rest.len(),
+            hand,
+            players,
+            user_id: row.user_id,
+            event: row.event.clone(),
+            cards: cards.clone(),
+            update: RoomMessageType::UpdateStackAndScore {
+                stack,
+                player_scores,
+                current_scores: Some(current_scores),
+            },
+        });
+    }
+    Some(steps)
+}
+
+#[cfg(test)]
+mod test {
+    use arraystring::ArrayString;
+    use lib_hearts::{PlayerState, TypeCard, PLAYER_NUMBER};
+    use uuid::Uuid;
+
+    use super::replay_steps;
+    use crate::{
+        data::{DealtCards, GameEvent, PlayerCard, RoomMessageType, RoomSettings, Trick},
+        db::{test_event_row, test_players},
+    };
+
+    fn card(position_in_deck: usize) -> PlayerCard {
+        PlayerCard {
+            type_card: TypeCard::Heart,
+            emoji: ArrayString::from_utf8("🂱").unwrap(),
+            position_in_deck,
+        }
+    }
+
+    #[test]
+    fn test_replay_steps() {
+        let game_id = Uuid::new_v4();
+        let players = test_players([(false, false); PLAYER_NUMBER]);
+        let ids = players.map(|p| p.id);
+        let scores = ids.map(|player_id| PlayerState {
+            player_id,
+            score: 1,
+        });
+        let row = |user_id, event| test_event_row(game_id, user_id, event);
+        let mut events = vec![
+            row(
+                None,
+                GameEvent::Started {
+                    room_id: Uuid::new_v4(),
+                    players,
+                    settings: RoomSettings::default(),
+                },
+            ),
+            row(
+                None,
+                GameEvent::Dealt {
+                    hand: 1,
+                    cards: [0, 1, 2, 3].map(|i| DealtCards {
+                        player_id: ids[i],
+                        cards: vec![card(i), card(i + 4)],
+                    }),
+                },
+            ),
+        ];
+        events.extend((0..PLAYER_NUMBER).map(|i| row(Some(ids[i]), GameEvent::Played(card(i)))));
+        let trick = Trick {
+            cards: vec![],
+            leader: ids[0],
+            winner: ids[3],
+            points: 4,
+        };
+        events.push(row(Some(ids[3]), GameEvent::TrickTaken(trick)));
+        events.push(row(Some(ids[3]), GameEvent::Played(card(7))));
+        events.push(row(
+            None,
+            GameEvent::Ended {
+                player_scores: scores,
+                winners: vec![],
+            },
+        ));
+
+        // unfinished games are not replayed
+        assert!(replay_steps(&events[..events.len() - 1]).is_none());
+
+        let steps = replay_steps(&events).unwrap();
+        assert_eq!(events.len() - 1, steps.len());
+        assert!(steps.iter().all(|step| step.steps == steps.len()));
+
+        let RoomMessageType::UpdateStackAndScore {
+            stack,
+            current_scores,
+            ..
+        } = &steps[5].update
+        else {
+            panic!("not an update {:?}", steps[5].update);
+        };
+        assert_eq!([0, 1, 2, 3].map(|i| Some(card(i))), *stack);
+        assert_eq!(4, current_scores.unwrap()[3].score);
+        assert_eq!(vec![card(4)], steps[5].cards[0]);
+
+        // the next card starts a new trick
+        let RoomMessageType::UpdateStackAndScore {
+            stack,
+            player_scores,
+            ..
+        } = &steps[7].update
+        else {
+            panic!("not an update {:?}", steps[7].update);
+        };
+        assert_eq!([Some(card(7)), None, None, None], *stack);
+        assert!(steps[7].cards[3].is_empty());
+        assert_eq!(scores, *player_scores);
+    }
+}
@@ -1,29 +1,49 @@
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     error::Error,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use crate::data::{
-    CardStack, PlayerCard, Room, RoomMessage, RoomMessageType, RoomState, User, UserId,
+    CardStack, DbRoom, DealtCards, EndCondition, EventLog, GameEvent, GameEventRow, GameRecord,
+    Lobby, LobbyUpdate, Outbox, PlayedCard, PlayerCard, PlayerErrorKind, Room, RoomMessage,
+    RoomMessageType, RoomSettings, RoomState, RoomStatus, RoomSummary, Rooms, SessionScores, Trick,
+    User, UserId,
 };
 use crate::{
     constants::{
         ABRITRATRY_CHANNEL_CAPACITY, BOT_SLEEP_SECS, COMPUTE_SCORE_DELAY_SECS, DEFAULT_HANDS,
-        TIMEOUT_SECS,
+        DEFAULT_SCORE_THRESHOLD, DISCONNECT_GRACE_SECS, EVENT_LOG_SIZE, FINISHED_ROOM_GRACE_SECS,
+        LOBBY_CHANNEL_CAPACITY, MAX_DELAY_SECS, MAX_HANDS, MAX_SCORE_THRESHOLD, MAX_TIMEOUT_SECS,
+        PUBLIC_CHANNEL_CAPACITY, TIMEOUT_SECS, USER_CHANNEL_CAPACITY,
     },
-    db::find_user_by_id,
-    utils::to_static_array,
+    db::{
+        archive_room, delete_room, delete_room_viewer, find_credentials_by_name, find_user_by_id,
+        insert_game_event, upsert_room, upsert_user,
+    },
+    rating::record_game_ratings,
+    stats::record_game_stats,
+    user::validate_name,
 };
 use arraystring::ArrayString;
-use async_broadcast::{InactiveReceiver, Receiver, Sender};
+use async_broadcast::{Receiver, RecvError, TrySendError};
+use chrono::Utc;
+use futures::{stream, Stream, StreamExt};
 use lib_hearts::{
-    get_card_by_idx, Card, Game, GameError, GameState, PLAYER_CARD_SIZE, PLAYER_NUMBER,
+    get_card_by_idx, Card, Game, GameError, GameState, PlayerState, PositionInDeck,
+    NUMBER_REPLACEABLE_CARDS, PLAYER_CARD_SIZE, PLAYER_NUMBER,
 };
 use sqlx::{Pool, Sqlite};
-use tokio::{sync::RwLock, time::timeout};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError as UserTrySendError},
+        RwLock,
+    },
+    task::JoinHandle,
+    time::timeout,
+};
 use uuid::Uuid;
 
 fn convert_card_to_player_card(card: Option<(usize, &Card)>) -> Option<PlayerCard> {
@@ -38,6 +58,9 @@ fn convert_card_to_player_card(card: Option<(usize, &Card)>) -> Option<PlayerCar
         None
     }
 }
+fn player_card(card_idx: PositionInDeck) -> Option<PlayerCard> {
+    convert_card_to_player_card(Some((card_idx, get_card_by_idx(card_idx))))
+}
 fn convert_stack_to_card_player_card(stack: &CardStack) -> [Option<PlayerCard>; PLAYER_NUMBER] {
     stack.map(|s| {
         if let Some((_, card_idx)) = s {
@@ -48,48 +71,750 @@ fn convert_stack_to_card_player_card(stack: &CardStack) -> [Option<PlayerCard>;
     })
 }
 
+// the stack is in play order, the trick was led by the first card
+fn completed_trick(stack: &CardStack, game: &Game, winner: UserId) -> Option<Trick> {
+    let player_ids = game.player_ids_in_order();
+    let mut cards = Vec::with_capacity(PLAYER_NUMBER);
+    let mut points = 0;
+    for (player_pos, card_idx) in stack.iter().flatten() {
+        let card = get_card_by_idx(*card_idx);
+        points += card.get_value();
+        cards.push(PlayedCard {
+            player_id: player_ids[*player_pos],
+            card: convert_card_to_player_card(Some((*card_idx, card)))?,
+        });
+    }
+    Some(Trick {
+        leader: cards.first()?.player_id,
+        cards,
+        winner,
+        points,
+    })
+}
+
+// cards the player can play, none if it's not their turn to play.
+// validate_play checks the lead suit and whether hearts are broken
+fn legal_moves(game: &Game, player_id: UserId) -> Option<Vec<PositionInDeck>> {
+    if game.current_player_id() != Some(player_id)
+        || !matches!(game.state, GameState::PlayingHand { .. })
+    {
+        return None;
+    }
+    let legal_moves = game
+        .get_player_cards(player_id)
+        .iter()
+        .flatten()
+        .map(|(position_in_deck, _)| *position_in_deck)
+        .filter(|position_in_deck| game.validate_play(*position_in_deck).is_ok())
+        .collect();
+    Some(legal_moves)
+}
+
+// the public event doesn't reveal any hand,
+// the current player gets its cards and legal moves privately
+async fn send_legal_moves(
+    game: &Game,
+    uuid: Uuid,
+    outbox: &Outbox,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (Some(current_player_id), GameState::PlayingHand { stack, .. }) =
+        (game.current_player_id(), &game.state)
+    else {
+        return Ok(());
+    };
+    if game.current_player_is_bot() {
+        return Ok(());
+    }
+    let cards: [Option<PlayerCard>; PLAYER_CARD_SIZE] = game
+        .get_player_cards(current_player_id)
+        .map(convert_card_to_player_card);
+    outbox
+        .send(RoomMessage::to(
+            current_player_id,
+            RoomMessageType::NextPlayerToPlay {
+                current_player_id,
+                current_cards: Some(cards),
+                legal_moves: legal_moves(game, current_player_id),
+                uuid,
+                stack: convert_stack_to_card_player_card(stack),
+            },
+        ))
+        .await?;
+    Ok(())
+}
+
+fn is_owner(room: &Room, user_id: UserId) -> bool {
+    room.owner.is_none() || room.owner == Some(user_id)
+}
+
+async fn send_player_error(
+    outbox: &Outbox,
+    to_user_id: UserId,
+    kind: PlayerErrorKind,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    outbox
+        .send(RoomMessage::to(
+            to_user_id,
+            RoomMessageType::PlayerError(kind),
+        ))
+        .await?;
+    Ok(())
+}
+
 // only JOIN and get state are allowed for viewers
 fn is_valid_msg(room: &Room, user_id: UserId) -> bool {
     !room.viewers.contains(&user_id)
 }
 
+// players sorted the same way as game.player_ids_in_order()
+fn players_in_order(users: &[User; PLAYER_NUMBER], game: &Game) -> [User; PLAYER_NUMBER] {
+    game.player_ids_in_order().map(|id| {
+        users
+            .iter()
+            .find(|u| u.id == id)
+            .copied()
+            .unwrap_or_else(|| User::default().with_id(id))
+    })
+}
+
+impl From<GameError> for PlayerErrorKind {
+    fn from(e: GameError) -> Self {
+        PlayerErrorKind::Game(e)
+    }
+}
+
+impl RoomState {
+    pub fn seats(&self) -> [Option<User>; PLAYER_NUMBER] {
+        match self {
+            RoomState::WaitingForPlayers(players) => *players,
+            RoomState::Started(users, _) | RoomState::Done(users, _) => users.map(Some),
+        }
+    }
+
+    // replace the seated user with the same id, e.g after a name change
+    pub fn update_user(&mut self, user: User) {
+        match self {
+            RoomState::WaitingForPlayers(players) => {
+                for player in players.iter_mut().flatten().filter(|p| p.id == user.id) {
+                    *player = user;
+                }
+            }
+            RoomState::Started(users, _) | RoomState::Done(users, _) => {
+                for u in users.iter_mut().filter(|u| u.id == user.id) {
+                    *u = user;
+                }
+            }
+        }
+    }
+
+    // only possible while waiting for players
+    fn free_seat(&mut self, user_id: UserId) -> bool {
+        let RoomState::WaitingForPlayers(players) = self else {
+            return false;
+        };
+        let Some(seat) = players
+            .iter_mut()
+            .find(|p| p.is_some_and(|p| p.id == user_id))
+        else {
+            return false;
+        };
+        *seat = None;
+        true
+    }
+
+    fn is_name_taken(&self, user_id: UserId, name: &str) -> bool {
+        self.seats()
+            .iter()
+            .flatten()
+            .any(|u| u.id != user_id && u.name.as_str() == name)
+    }
+}
+
+impl Default for RoomSettings {
+    fn default() -> Self {
+        RoomSettings {
+            hands: DEFAULT_HANDS,
+            timeout_secs: TIMEOUT_SECS as u64,
+            bot_sleep_secs: BOT_SLEEP_SECS,
+            compute_score_delay_secs: COMPUTE_SCORE_DELAY_SECS,
+            end_condition: EndCondition::FixedHands,
+            score_threshold: DEFAULT_SCORE_THRESHOLD,
+        }
+    }
+}
+
+impl RoomSettings {
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if !(1..=MAX_HANDS).contains(&self.hands) {
+            return Err("invalid number of hands");
+        }
+        if !(1..=MAX_TIMEOUT_SECS).contains(&self.timeout_secs) {
+            return Err("invalid turn timeout");
+        }
+        if self.bot_sleep_secs > MAX_DELAY_SECS || self.compute_score_delay_secs > MAX_DELAY_SECS {
+            return Err("invalid delay");
+        }
+        if self.end_condition == EndCondition::ScoreThreshold
+            && !(1..=MAX_SCORE_THRESHOLD).contains(&self.score_threshold)
+        {
+            return Err("invalid score threshold");
+        }
+        Ok(())
+    }
+
+    // number of hands given to the game. when playing to a score, the game
+    // only ends by itself if no one ever reaches it
+    pub fn game_hands(&self) -> u8 {
+        match self.end_condition {
+            EndCondition::FixedHands => self.hands,
+            EndCondition::ScoreThreshold => u8::MAX - 1,
+        }
+    }
+}
+
+// lowest score wins. there could be more than one winner
+fn winners(player_scores: &[PlayerState; PLAYER_NUMBER]) -> Vec<UserId> {
+    let Some(min_score) = player_scores.iter().map(|p| p.score).min() else {
+        return vec![];
+    };
+    player_scores
+        .iter()
+        .filter(|p| p.score == min_score)
+        .map(|p| p.player_id)
+        .collect()
+}
+
+// checked after the score of a trick has been computed
+fn is_game_over(game: &Game, settings: &RoomSettings) -> bool {
+    match (&game.state, settings.end_condition) {
+        (GameState::End, _) => true,
+        (GameState::EndHand, EndCondition::ScoreThreshold) => {
+            let player_scores = game.player_score_by_id();
+            // on a tie for the lowest score, another hand is played
+            player_scores
+                .iter()
+                .any(|p| p.score >= settings.score_threshold)
+                && winners(&player_scores).len() == 1
+        }
+        _ => false,
+    }
+}
+
+fn end_message(game: &Game, record: &GameRecord) -> RoomMessageType {
+    let player_scores = game.player_score_by_id();
+    RoomMessageType::End {
+        player_scores,
+        winners: winners(&player_scores),
+        game_id: record.game_id,
+        session: record.session.clone(),
+    }
+}
+
+// save the room, so it can be restored after a restart, and show it in the lobby
+async fn persist_room(room: &Room) {
+    if let Err(e) = upsert_room(room, &room.pool).await {
+        tracing::error!("could not persist room {}: {e}", room.id);
+    }
+    room.lobby.publish(room.summary());
+}
+
+impl Lobby {
+    pub fn new() -> Self {
+        let (mut sender, receiver) = async_broadcast::broadcast(LOBBY_CHANNEL_CAPACITY);
+        // a lagging page loses the oldest updates, its feed then starts over
+        sender.set_overflow(true);
+        Lobby {
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            sender,
+            receiver: receiver.deactivate(),
+        }
+    }
+
+    // open tables first
+    pub fn rooms(&self) -> Vec<RoomSummary> {
+        let rooms = self.rooms.lock().expect("lobby lock poisoned");
+        sorted_rooms(&rooms)
+    }
+
+    // the rooms, then the updates that come after them
+    pub fn subscribe(&self) -> (Vec<RoomSummary>, Receiver<LobbyUpdate>) {
+        let rooms = self.rooms.lock().expect("lobby lock poisoned");
+        (sorted_rooms(&rooms), self.sender.new_receiver())
+    }
+
+    // the rooms, then every change to them
+    pub fn feed(&self) -> impl Stream<Item = LobbyUpdate> {
+        let (rooms, updates) = self.subscribe();
+        let lobby = self.clone();
+        let updates = stream::unfold((updates, lobby), |(mut updates, lobby)| async move {
+            let update = match updates.recv_direct().await {
+                Ok(update) => update,
+                Err(RecvError::Overflowed(_)) => LobbyUpdate::Reset(lobby.rooms()),
+                Err(RecvError::Closed) => return None,
+            };
+            Some((update, (updates, lobby)))
+        });
+        stream::iter(rooms.into_iter().map(LobbyUpdate::Room)).chain(updates)
+    }
+
+    pub fn publish(&self, summary: RoomSummary) {
+        let mut rooms = self.rooms.lock().expect("lobby lock poisoned");
+        if rooms.get(&summary.id) == Some(&summary) {
+            return;
+        }
+        rooms.insert(summary.id, summary.clone());
+        self.broadcast(LobbyUpdate::Room(summary));
+    }
+
+    pub fn remove(&self, id: Uuid) {
+        let mut rooms = self.rooms.lock().expect("lobby lock poisoned");
+        if rooms.remove(&id).is_some() {
+            self.broadcast(LobbyUpdate::Removed(id));
+        }
+    }
+
+    // called under the rooms lock, so pages get the updates in order
+    fn broadcast(&self, update: LobbyUpdate) {
+        match self.sender.try_broadcast(update) {
+            // no lobby page is open
+            Ok(_) | Err(TrySendError::Inactive(_)) => {}
+            Err(e) => tracing::error!("could not update the lobby: {e:?}"),
+        }
+    }
+}
+
+fn sorted_rooms(rooms: &HashMap<Uuid, RoomSummary>) -> Vec<RoomSummary> {
+    let mut rooms: Vec<RoomSummary> = rooms.values().cloned().collect();
+    rooms.sort_by_key(|room| (room.status, room.id));
+    rooms
+}
+
+// the sequence number is only set by the outbox
+impl RoomMessage {
+    // from the room to everyone
+    pub fn public(msg_type: RoomMessageType) -> Self {
+        RoomMessage {
+            from_user_id: None,
+            to_user_id: None,
+            seq: None,
+            msg_type,
+        }
+    }
+    // from the room to one user
+    pub fn to(user_id: UserId, msg_type: RoomMessageType) -> Self {
+        RoomMessage {
+            from_user_id: None,
+            to_user_id: Some(user_id),
+            seq: None,
+            msg_type,
+        }
+    }
+    // from a user to the room
+    pub fn from_user(user_id: UserId, msg_type: RoomMessageType) -> Self {
+        RoomMessage {
+            from_user_id: Some(user_id),
+            to_user_id: None,
+            seq: None,
+            msg_type,
+        }
+    }
+}
+
+impl Outbox {
+    pub fn new() -> Self {
+        let (mut room_sender, room_receiver) =
+            async_broadcast::broadcast(ABRITRATRY_CHANNEL_CAPACITY);
+        // once the room task is gone, sending to the room fails instead of waiting for it forever
+        room_sender.set_await_active(false);
+        let (mut public_sender, public_receiver) =
+            async_broadcast::broadcast(PUBLIC_CHANNEL_CAPACITY);
+        // a slow socket must not hold the room back, it loses the oldest events instead
+        public_sender.set_overflow(true);
+        Outbox {
+            room_sender,
+            room_receiver: room_receiver.deactivate(),
+            public_sender,
+            public_receiver: public_receiver.deactivate(),
+            user_senders: Arc::new(Mutex::new(HashMap::new())),
+            event_log: Arc::new(Mutex::new(EventLog::default())),
+        }
+    }
+
+    // send a message from the room to the sockets
+    pub async fn send(&self, msg: RoomMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(to_user_id) = msg.to_user_id {
+            let seq = self.event_log.lock().expect("event log lock poisoned").seq;
+            self.send_to_user(
+                to_user_id,
+                RoomMessage {
+                    seq: Some(seq),
+                    ..msg
+                },
+            );
+            return Ok(());
+        }
+        // the room task and the timeout bots react to public events
+        self.room_sender.broadcast_direct(msg.clone()).await?;
+        self.publish(msg)
+    }
+
+    // tell the sockets and the timeout bots the room is gone, when its task stops
+    pub fn close(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let msg = RoomMessage::public(RoomMessageType::RoomClosed);
+        match self.room_sender.try_broadcast(msg.clone()) {
+            Ok(_)
+            | Err(TrySendError::Inactive(_))
+            | Err(TrySendError::Full(_))
+            | Err(TrySendError::Closed(_)) => {}
+        }
+        // the sockets and the grace periods that outlive the room must not queue up
+        self.room_sender.close();
+        self.publish(msg)
+    }
+
+    fn publish(&self, msg: RoomMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // stamp and broadcast under the same lock so sockets get the events in order
+        let mut event_log = self.event_log.lock().expect("event log lock poisoned");
+        event_log.seq += 1;
+        let msg = RoomMessage {
+            seq: Some(event_log.seq),
+            ..msg
+        };
+        if event_log.events.len() == EVENT_LOG_SIZE {
+            event_log.events.pop_front();
+        }
+        event_log.events.push_back(msg.clone());
+        match self.public_sender.try_broadcast(msg) {
+            // no socket is listening
+            Ok(_) | Err(TrySendError::Inactive(_)) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    // send a message to the room task, on behalf of a user
+    pub async fn send_to_room(&self, msg: RoomMessage) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.room_sender.broadcast_direct(msg).await?;
+        Ok(())
+    }
+
+    fn send_to_user(&self, user_id: UserId, msg: RoomMessage) {
+        let mut user_senders = self
+            .user_senders
+            .lock()
+            .expect("user senders lock poisoned");
+        let Some(senders) = user_senders.get_mut(&user_id) else {
+            tracing::debug!("{user_id} has no socket open, dropping {msg:?}");
+            return;
+        };
+        // dropping the sender closes the socket, the client gets
+        // the current state when it reconnects
+        senders.retain(|sender| match sender.try_send(msg.clone()) {
+            Ok(_) => true,
+            Err(UserTrySendError::Full(_)) => {
+                tracing::warn!("socket of {user_id} is too slow, closing it");
+                false
+            }
+            Err(UserTrySendError::Closed(_)) => false,
+        });
+        if senders.is_empty() {
+            user_senders.remove(&user_id);
+        }
+    }
+
+    // private channel for one socket of the user
+    pub fn register(&self, user_id: UserId) -> mpsc::Receiver<RoomMessage> {
+        let (sender, receiver) = mpsc::channel(USER_CHANNEL_CAPACITY);
+        let mut user_senders = self
+            .user_senders
+            .lock()
+            .expect("user senders lock poisoned");
+        let senders = user_senders.entry(user_id).or_default();
+        senders.retain(|sender| !sender.is_closed());
+        senders.push(sender);
+        receiver
+    }
+
+    // send the events after last_seq to the user again,
+    // false if some of them are not in the log anymore
+    pub fn replay(&self, user_id: UserId, last_seq: u64) -> bool {
+        let events: Vec<RoomMessage> = {
+            let event_log = self.event_log.lock().expect("event log lock poisoned");
+            let first_seq = event_log
+                .events
+                .front()
+                .and_then(|msg| msg.seq)
+                .unwrap_or(event_log.seq + 1);
+            // the room may have been restarted since, which resets the sequence
+            if last_seq > event_log.seq || last_seq + 1 < first_seq {
+                return false;
+            }
+            event_log
+                .events
+                .iter()
+                .filter(|msg| msg.seq > Some(last_seq))
+                .cloned()
+                .collect()
+        };
+        for msg in events {
+            self.send_to_user(user_id, msg);
+        }
+        true
+    }
+
+    pub fn subscribe(&self) -> Receiver<RoomMessage> {
+        self.public_receiver.activate_cloned()
+    }
+}
+
+impl Default for Outbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GameEvent {
+    // stored next to the event, to query the log without parsing it
+    pub fn kind(&self) -> &'static str {
+        match self {
+            GameEvent::Started { .. } => "started",
+            GameEvent::Dealt { .. } => "dealt",
+            GameEvent::Exchanged { .. } => "exchanged",
+            GameEvent::Played(_) => "played",
+            GameEvent::TrickTaken(_) => "trickTaken",
+            GameEvent::HandScored { .. } => "handScored",
+            GameEvent::Ended { .. } => "ended",
+        }
+    }
+}
+
+impl GameRecord {
+    pub fn new(game_id: Option<Uuid>, session: SessionScores, pool: Pool<Sqlite>) -> Self {
+        let (events, mut receiver) = mpsc::unbounded_channel::<GameEventRow>();
+        tokio::spawn(async move {
+            while let Some(row) = receiver.recv().await {
+                if let Err(e) = insert_game_event(&row, &pool)
+                    .await
+                    .map_err(|e| e.to_string())
+                {
+                    tracing::error!("could not log {row:?}: {e}");
+                } else if let GameEvent::Ended { .. } = row.event {
+                    if let Err(e) = record_game_stats(row.game_id, &pool)
+                        .await
+                        .map_err(|e| e.to_string())
+                    {
+                        tracing::error!("could not record stats of game {}: {e}", row.game_id);
+                    }
+                    if let Err(e) = record_game_ratings(row.game_id, &pool)
+                        .await
+                        .map_err(|e| e.to_string())
+                    {
+                        tracing::error!("could not rate game {}: {e}", row.game_id);
+                    }
+                }
+            }
+        });
+        GameRecord {
+            game_id,
+            session,
+            tricks: Vec::new(),
+            events,
+        }
+    }
+
+    pub fn record(&self, user_id: Option<UserId>, event: GameEvent) {
+        let Some(game_id) = self.game_id else {
+            return;
+        };
+        let row = GameEventRow {
+            game_id,
+            user_id,
+            event,
+            created_at: Utc::now().timestamp(),
+        };
+        if let Err(e) = self.events.send(row) {
+            tracing::error!("game events writer is gone, dropping {:?}", e.0);
+        }
+    }
+
+    pub fn start(
+        &mut self,
+        room_id: Uuid,
+        users: &[User; PLAYER_NUMBER],
+        game: &Game,
+        settings: RoomSettings,
+    ) {
+        self.game_id = Some(Uuid::new_v4());
+        self.record(
+            None,
+            GameEvent::Started {
+                room_id,
+                players: players_in_order(users, game),
+                settings,
+            },
+        );
+        self.dealt(game);
+    }
+
+    pub fn dealt(&mut self, game: &Game) {
+        self.tricks.clear();
+        let cards = game.player_ids_in_order().map(|player_id| DealtCards {
+            player_id,
+            cards: game
+                .get_player_cards(player_id)
+                .into_iter()
+                .filter_map(convert_card_to_player_card)
+                .collect(),
+        });
+        self.record(
+            None,
+            GameEvent::Dealt {
+                hand: game.current_hand,
+                cards,
+            },
+        );
+    }
+
+    // exchanged cards go to the next player in the order of the game
+    pub fn exchanged(
+        &self,
+        game: &Game,
+        player_id: UserId,
+        positions: [PositionInDeck; NUMBER_REPLACEABLE_CARDS],
+    ) {
+        let player_ids = game.player_ids_in_order();
+        let Some(pos) = player_ids.iter().position(|id| *id == player_id) else {
+            return;
+        };
+        let [Some(first), Some(second), Some(third)] = positions.map(player_card) else {
+            return;
+        };
+        self.record(
+            Some(player_id),
+            GameEvent::Exchanged {
+                to_player_id: player_ids[(pos + 1) % PLAYER_NUMBER],
+                cards: [first, second, third],
+            },
+        );
+    }
+
+    // the last card of the stack was just played
+    pub fn played(&self, game: &Game) {
+        let (GameState::PlayingHand { stack, .. } | GameState::ComputeScore { stack, .. }) =
+            &game.state
+        else {
+            return;
+        };
+        let Some((player_pos, card_idx)) = stack.iter().flatten().last() else {
+            return;
+        };
+        if let Some(card) = player_card(*card_idx) {
+            let player_id = game.player_ids_in_order()[*player_pos];
+            self.record(Some(player_id), GameEvent::Played(card));
+        }
+    }
+
+    pub fn trick_taken(&mut self, trick: Trick) {
+        self.record(Some(trick.winner), GameEvent::TrickTaken(trick.clone()));
+        self.tricks.push(trick);
+    }
+
+    pub fn ended(&mut self, game: &Game) {
+        let player_scores = game.player_score_by_id();
+        self.session.add(&player_scores);
+        self.record(
+            None,
+            GameEvent::Ended {
+                player_scores,
+                winners: winners(&player_scores),
+            },
+        );
+    }
+}
+
+impl SessionScores {
+    pub fn add(&mut self, player_scores: &[PlayerState]) {
+        self.matches += 1;
+        for player_state in player_scores {
+            match self
+                .player_scores
+                .iter_mut()
+                .find(|p| p.player_id == player_state.player_id)
+            {
+                Some(p) => p.score += player_state.score,
+                None => self.player_scores.push(*player_state),
+            }
+        }
+    }
+}
+
 impl Room {
-    pub async fn new(pool: Pool<Sqlite>) -> (Uuid, Arc<RwLock<Room>>) {
-        let (sender, receiver) = async_broadcast::broadcast(ABRITRATRY_CHANNEL_CAPACITY);
-        let inactive_receiver = receiver.deactivate();
-        let id = Uuid::new_v4();
-        let room = Room {
-            id,
+    pub async fn new(
+        pool: Pool<Sqlite>,
+        rooms: Rooms,
+        lobby: Lobby,
+        settings: RoomSettings,
+        owner: UserId,
+    ) -> (Uuid, Arc<RwLock<Room>>) {
+        let db_room = DbRoom {
+            id: Uuid::new_v4(),
+            settings,
+            owner: Some(owner),
             bots: [None; PLAYER_NUMBER],
             state: RoomState::WaitingForPlayers([None; PLAYER_NUMBER]),
             viewers: HashSet::with_capacity(5),
-            sender: Some(sender),
-            receiver: inactive_receiver,
+            game_id: None,
+            session: SessionScores::default(),
+        };
+        let (id, room) = Room::from_db(db_room, pool, rooms, lobby).await;
+        persist_room(&*room.read().await).await;
+        (id, room)
+    }
+    pub async fn from_db(
+        db_room: DbRoom,
+        pool: Pool<Sqlite>,
+        rooms: Rooms,
+        lobby: Lobby,
+    ) -> (Uuid, Arc<RwLock<Room>>) {
+        let id = db_room.id;
+        let room = Room {
+            id,
+            bots: db_room.bots,
+            state: db_room.state,
+            settings: db_room.settings,
+            owner: db_room.owner,
+            viewers: db_room.viewers,
+            rematch_votes: HashSet::new(),
+            record: GameRecord::new(db_room.game_id, db_room.session, pool.clone()),
+            connections: HashMap::new(),
+            disconnected_since: HashMap::new(),
+            idle_since: Some(Instant::now()),
+            outbox: Outbox::new(),
+            lobby,
             task: None,
             pool,
         };
+        room.lobby.publish(room.summary());
         let room = Arc::new(RwLock::new(room));
-        Room::restart(room.clone()).await;
+        Room::restart(room.clone(), rooms).await;
 
         (id, room)
     }
-    pub async fn restart(room: Arc<RwLock<Room>>) -> InactiveReceiver<RoomMessage> {
-        let (is_finished, pool, id, inactive_receiver) = {
+    pub async fn restart(room: Arc<RwLock<Room>>, rooms: Rooms) {
+        let (is_finished, pool, id) = {
             let rg = room.read().await;
-            (
-                rg.is_finished(),
-                rg.pool.clone(),
-                rg.id,
-                rg.receiver.clone(),
-            )
+            (rg.is_finished(), rg.pool.clone(), rg.id)
         };
 
         if is_finished {
             tracing::warn!("room task has been cancelled / finished, try to restart...");
-            let (sender, receiver) = async_broadcast::broadcast(ABRITRATRY_CHANNEL_CAPACITY);
-            let inactive_receiver = receiver.deactivate();
+            // the channels must be in place before the task starts, otherwise
+            // the task could listen to the previous ones
+            let mut rg = room.write().await;
+            rg.outbox = Outbox::new();
+            // listen before the sockets can reach the room
+            let receiver = rg.outbox.room_sender.new_receiver();
 
-            let task = room_task(room.clone(), pool, id);
+            let task = room_task(room.clone(), receiver, pool, id, rooms);
             let task = tokio::spawn(async move {
                 match task.await {
                     v @ Ok(_) => {
@@ -102,14 +827,9 @@ impl Room {
                     }
                 }
             });
-            let mut rg = room.write().await;
             rg.task = Some(task);
-            rg.sender = Some(sender);
-            rg.receiver = inactive_receiver;
-            rg.receiver.clone()
         } else {
             tracing::warn!("task {id} is already running");
-            inactive_receiver
         }
     }
     pub fn is_finished(&self) -> bool {
@@ -119,13 +839,162 @@ impl Room {
             true
         }
     }
+    pub fn summary(&self) -> RoomSummary {
+        RoomSummary {
+            id: self.id,
+            status: match self.state {
+                RoomState::WaitingForPlayers(_) => RoomStatus::Waiting,
+                RoomState::Started(..) => RoomStatus::Playing,
+                RoomState::Done(..) => RoomStatus::Done,
+            },
+            seats: self.state.seats(),
+            viewers: self.viewers.len(),
+            settings: self.settings,
+        }
+    }
+    // nobody connected for too long, finished rooms only get a short grace
+    pub fn is_idle(&self, idle: Duration) -> bool {
+        let Some(idle_since) = self.idle_since else {
+            return false;
+        };
+        let idle = if matches!(self.state, RoomState::Done(..)) {
+            idle.min(Duration::from_secs(FINISHED_ROOM_GRACE_SECS))
+        } else {
+            idle
+        };
+        idle_since.elapsed() >= idle
+    }
+    // a socket of the user opened, true if it is the first one
+    pub fn connect(&mut self, user_id: UserId) -> bool {
+        self.idle_since = None;
+        self.disconnected_since.remove(&user_id);
+        let count = self.connections.entry(user_id).or_default();
+        *count += 1;
+        *count == 1
+    }
+    // a socket of the user closed, true if it was the last one.
+    // a player leaving a started game gets a grace period before a bot takes over
+    pub fn disconnect(&mut self, user_id: UserId, now: Instant) -> bool {
+        let Some(count) = self.connections.get_mut(&user_id) else {
+            return false;
+        };
+        *count -= 1;
+        if *count > 0 {
+            return false;
+        }
+        self.connections.remove(&user_id);
+        if self.connections.is_empty() {
+            self.idle_since = Some(now);
+        }
+        if matches!(self.state, RoomState::Started(..)) && self.is_human_seat(user_id) {
+            self.disconnected_since.insert(user_id, now);
+        }
+        true
+    }
+    // a bot plays for the player whose grace period is over, true if it took the seat
+    pub fn take_over_seat(&mut self, user_id: UserId, now: Instant) -> bool {
+        // the player may have come back and left again since
+        let grace_over = self.disconnected_since.get(&user_id).is_some_and(|since| {
+            now.duration_since(*since) >= Duration::from_secs(DISCONNECT_GRACE_SECS)
+        });
+        if !grace_over
+            || !matches!(self.state, RoomState::Started(..))
+            || !self.is_human_seat(user_id)
+            || self.bots.contains(&Some(user_id))
+        {
+            return false;
+        }
+        let Some(bot_slot) = self.bots.iter_mut().find(|b| b.is_none()) else {
+            tracing::warn!("no bot seat left for {user_id}");
+            return false;
+        };
+        *bot_slot = Some(user_id);
+        true
+    }
+    // a human seat in the bots means a bot took it over, true if the player got it back
+    pub fn reclaim_seat(&mut self, user_id: UserId) -> bool {
+        if !self.is_human_seat(user_id) {
+            return false;
+        }
+        let Some(bot_slot) = self.bots.iter_mut().find(|b| **b == Some(user_id)) else {
+            return false;
+        };
+        *bot_slot = None;
+        true
+    }
+    fn is_human_seat(&self, user_id: UserId) -> bool {
+        self.state
+            .seats()
+            .iter()
+            .flatten()
+            .any(|u| u.id == user_id && !u.bot)
+    }
+    // stops the room task, closes the sockets and archives the room
+    pub async fn shutdown(&mut self) {
+        if let Some(task) = self.task.take() {
+            task.abort();
+        }
+        if let Err(e) = self.outbox.close() {
+            tracing::error!("could not close the sockets of room {}: {e:?}", self.id);
+        }
+        persist_room(self).await;
+        if let Err(e) = archive_room(self.id, Utc::now().timestamp(), &self.pool).await {
+            tracing::error!("could not archive room {}: {e}", self.id);
+        }
+        self.lobby.remove(self.id);
+    }
+}
+
+// whether the user has a seat at one of the tables
+pub async fn is_seated(rooms: &Rooms, user_id: UserId) -> bool {
+    // don't hold the map while waiting for the rooms
+    let candidates: Vec<Arc<RwLock<Room>>> = rooms.iter().map(|e| e.value().clone()).collect();
+    for room in candidates {
+        if room
+            .read()
+            .await
+            .state
+            .seats()
+            .iter()
+            .flatten()
+            .any(|u| u.id == user_id)
+        {
+            return true;
+        }
+    }
+    false
+}
+
+// archives and evicts the idle rooms
+pub fn spawn_reaper(rooms: Rooms, idle: Duration, period: Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            // don't hold the map while waiting for the rooms
+            let candidates: Vec<(Uuid, Arc<RwLock<Room>>)> = rooms
+                .iter()
+                .map(|e| (*e.key(), e.value().clone()))
+                .collect();
+            for (id, room) in candidates {
+                let mut room_guard = room.write().await;
+                if !room_guard.is_idle(idle) {
+                    continue;
+                }
+                room_guard.shutdown().await;
+                rooms.remove(&id);
+                tracing::info!("room {id} was idle, archived");
+            }
+        }
+    })
 }
 
 async fn timeout_bot(
     room: Arc<RwLock<Room>>,
     mut player_id: Uuid,
     mut receiver: Receiver<RoomMessage>,
-    sender: Sender<RoomMessage>,
+    outbox: Outbox,
+    settings: RoomSettings,
 ) -> Result<(), Box<dyn Error + Sync + Send>> {
     tracing::debug!("spawned timeout for {player_id}");
 
@@ -136,7 +1005,7 @@ async fn timeout_bot(
     loop {
         let mut timed_out = true;
         let now = Instant::now();
-        let mut timeout_act = Duration::from_secs(TIMEOUT_SECS as u64);
+        let mut timeout_act = Duration::from_secs(settings.timeout_secs);
 
         while timeout_act != Duration::ZERO {
             tracing::debug!("entering timeout loop with a duration of {timeout_act:?}");
@@ -166,7 +1035,7 @@ async fn timeout_bot(
                         player_id = current_player_id;
                         timed_out = false;
                     }
-                    RoomMessageType::End { .. } => {
+                    RoomMessageType::End { .. } | RoomMessageType::RoomClosed => {
                         tracing::info!("game over. timeout bot");
                         return Ok(());
                     }
@@ -175,6 +1044,11 @@ async fn timeout_bot(
                         tracing::debug!("invalid message: {msg:?}");
                     }
                 },
+                // the room is gone, even if its RoomClosed didn't fit in the channel
+                Ok(Err(RecvError::Closed)) => {
+                    tracing::info!("room closed. timeout bot");
+                    return Ok(());
+                }
                 Ok(Err(e)) => {
                     timeout_act = sub_t(timeout_act, now.elapsed());
                     tracing::debug!("timeout: {e}");
@@ -192,12 +1066,8 @@ async fn timeout_bot(
                                                             // below again.
             let mut room_guard = room.write().await;
 
-            match sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: Some(player_id),
-                    msg_type: RoomMessageType::TimedOut,
-                })
+            match outbox
+                .send(RoomMessage::to(player_id, RoomMessageType::TimedOut))
                 .await
             {
                 Ok(res) => {
@@ -207,7 +1077,10 @@ async fn timeout_bot(
                     tracing::error!("message not sent => {e:?}");
                 }
             }
-            match play_bot(&mut room_guard.state, &sender).await {
+            let rg = &mut *room_guard;
+            let res = play_bot(&mut rg.state, &mut rg.record, &settings, &outbox).await;
+            persist_room(&room_guard).await;
+            match res {
                 Ok(res) => {
                     if let Some(p) = res {
                         player_id = p;
@@ -229,48 +1102,65 @@ async fn timeout_bot(
 // if game is done, return true
 async fn send_message_after_played(
     game: &mut Game,
-    sender: &Sender<RoomMessage>,
+    record: &mut GameRecord,
+    users: &[User; PLAYER_NUMBER],
+    settings: &RoomSettings,
+    outbox: &Outbox,
 ) -> Result<bool, Box<dyn Error + Send + Sync>> {
     let Some(current_player_id) = game.current_player_id() else {
         unreachable!()
     };
     let uuid = Uuid::new_v4();
+    record.played(game);
 
     match &mut game.state {
         GameState::PlayingHand { stack, .. } => {
-            sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: None,
-                    msg_type: RoomMessageType::NextPlayerToPlay {
-                        current_player_id,
-                        current_cards: None,
-                        uuid,
-                        stack: convert_stack_to_card_player_card(stack),
-                    },
-                })
+            outbox
+                .send(RoomMessage::public(RoomMessageType::NextPlayerToPlay {
+                    current_player_id,
+                    current_cards: None,
+                    legal_moves: None,
+                    uuid,
+                    stack: convert_stack_to_card_player_card(stack),
+                }))
                 .await?;
+            send_legal_moves(game, uuid, outbox).await?;
         }
         GameState::ComputeScore { ref stack, .. } => {
             let stack = *stack;
 
             game.compute_score()?;
+            // the player who took the trick plays next
+            let winner = game.current_player_id().ok_or("No current id")?;
+            if let Some(trick) = completed_trick(&stack, game, winner) {
+                record.trick_taken(trick);
+            }
+            if matches!(game.state, GameState::EndHand | GameState::End) {
+                record.record(
+                    None,
+                    GameEvent::HandScored {
+                        hand: game.current_hand - 1,
+                        player_scores: game.player_score_by_id(),
+                    },
+                );
+            }
 
             let current_scores = game.current_score_by_id();
             let player_scores = game.player_score_by_id();
-            sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: None,
-                    msg_type: RoomMessageType::UpdateStackAndScore {
-                        stack: convert_stack_to_card_player_card(&stack),
-                        current_scores: Some(current_scores),
-                        player_scores,
-                    },
-                })
+            outbox
+                .send(RoomMessage::public(RoomMessageType::UpdateStackAndScore {
+                    stack: convert_stack_to_card_player_card(&stack),
+                    current_scores: Some(current_scores),
+                    player_scores,
+                }))
                 .await?;
 
-            tokio::time::sleep(Duration::from_secs(COMPUTE_SCORE_DELAY_SECS)).await;
+            tokio::time::sleep(Duration::from_secs(settings.compute_score_delay_secs)).await;
+
+            if is_game_over(game, settings) {
+                game.state = GameState::End;
+                return Ok(true);
+            }
 
             match &game.state {
                 GameState::PlayingHand {
@@ -278,42 +1168,38 @@ async fn send_message_after_played(
                     current_scores,
                 } => {
                     let current_player_id = game.current_player_id().ok_or("No current id")?;
-                    sender
-                        .broadcast_direct(RoomMessage {
-                            from_user_id: None,
-                            to_user_id: None,
-                            msg_type: RoomMessageType::NextPlayerToPlay {
-                                current_player_id,
-                                uuid,
-                                current_cards: None,
-                                stack: convert_stack_to_card_player_card(stack),
-                            },
-                        })
+                    outbox
+                        .send(RoomMessage::public(RoomMessageType::NextPlayerToPlay {
+                            current_player_id,
+                            uuid,
+                            current_cards: None,
+                            legal_moves: None,
+                            stack: convert_stack_to_card_player_card(stack),
+                        }))
                         .await?;
+                    send_legal_moves(game, uuid, outbox).await?;
                 }
                 GameState::EndHand | GameState::ExchangeCards { commands: _ } => {
                     game.deal_cards()?;
+                    record.dealt(game);
                     let current_player_id = game.current_player_id().ok_or("should not happen")?;
 
                     let player_ids_in_order = game.player_ids_in_order();
                     let player_scores = game.player_score_by_id();
 
-                    sender
-                        .broadcast_direct(RoomMessage {
-                            from_user_id: None,
-                            to_user_id: None,
-                            msg_type: RoomMessageType::NewHand {
-                                player_ids_in_order,
-                                current_player_id,
-                                uuid,
-                                player_scores,
-                                hands: game.hands,
-                                current_hand: game.current_hand,
-                            },
-                        })
+                    outbox
+                        .send(RoomMessage::public(RoomMessageType::NewHand {
+                            player_ids_in_order,
+                            players: players_in_order(users, game),
+                            current_player_id,
+                            uuid,
+                            player_scores,
+                            hands: game.hands,
+                            current_hand: game.current_hand,
+                        }))
                         .await?;
                 }
-                GameState::End => return Ok(true), // FIXME probably send something brazza
+                GameState::End => return Ok(true),
                 e => unreachable!("this cannot happen brazza {e:?}"),
             }
         }
@@ -325,45 +1211,60 @@ async fn send_message_after_played(
 
 async fn play_bot(
     state: &mut RoomState,
-    sender: &Sender<RoomMessage>,
+    record: &mut GameRecord,
+    settings: &RoomSettings,
+    outbox: &Outbox,
 ) -> Result<Option<UserId>, Box<dyn Error + Send + Sync>> {
     if let RoomState::Started(ref users, ref mut game) = state {
         // we don't check if player
         // is a bot or not, in order to be able to implement timeout later
         match &game.state {
             GameState::ExchangeCards { .. } => {
-                tokio::time::sleep(Duration::from_secs(BOT_SLEEP_SECS)).await; // give some delay
+                tokio::time::sleep(Duration::from_secs(settings.bot_sleep_secs)).await; // give some delay
 
+                let player_id = game.current_player_id().ok_or("should not happen")?;
+                let before: Vec<PositionInDeck> = game
+                    .get_player_cards(player_id)
+                    .iter()
+                    .flatten()
+                    .map(|(position_in_deck, _)| *position_in_deck)
+                    .collect();
                 if let e @ Err(_) = game.play_bot() {
                     tracing::error!("exchange cards error");
                     game.print_state();
                     e?;
                 }
+                // the bot chose the cards it doesn't have anymore
+                let after = game.get_player_cards(player_id);
+                let exchanged: Vec<PositionInDeck> = before
+                    .into_iter()
+                    .filter(|position_in_deck| {
+                        !after.iter().flatten().any(|(p, _)| p == position_in_deck)
+                    })
+                    .collect();
+                if let Ok(exchanged) = exchanged.try_into() {
+                    record.exchanged(game, player_id, exchanged);
+                }
                 let Some(next_player_id) = game.current_player_id() else {
                     unreachable!()
                 };
                 tracing::debug!("after exchange cards, send message for next {next_player_id}");
-                send_message_after_cards_replaced(game, sender, next_player_id).await?;
+                send_message_after_cards_replaced(game, outbox, next_player_id).await?;
                 return Ok(game.current_player_id());
             }
             GameState::PlayingHand {
                 stack: _,
                 current_scores: _,
             } => {
-                tokio::time::sleep(Duration::from_secs(BOT_SLEEP_SECS)).await; // give some delay
+                tokio::time::sleep(Duration::from_secs(settings.bot_sleep_secs)).await; // give some delay
                 game.play_bot()?;
                 let current_player_id = game.current_player_id();
-                if send_message_after_played(game, sender).await? {
+                if send_message_after_played(game, record, users, settings, outbox).await? {
                     // game is done, update state
-                    let player_scores = game.player_score_by_id();
+                    record.ended(game);
+                    let msg_type = end_message(game, record);
                     *state = RoomState::Done(*users, *game);
-                    sender
-                        .broadcast_direct(RoomMessage {
-                            from_user_id: None,
-                            to_user_id: None,
-                            msg_type: RoomMessageType::End { player_scores },
-                        })
-                        .await?;
+                    outbox.send(RoomMessage::public(msg_type)).await?;
                 }
                 return Ok(current_player_id);
             }
@@ -377,7 +1278,9 @@ async fn play_bot(
 
 async fn bot_task(
     state: &mut RoomState,
-    sender: &Sender<RoomMessage>,
+    record: &mut GameRecord,
+    settings: &RoomSettings,
+    outbox: &Outbox,
     msg: RoomMessage,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     match msg.msg_type {
@@ -398,7 +1301,7 @@ async fn bot_task(
             current_player_id, ..
         } => {
             tracing::debug!("LINE 401 {current_player_id}");
-            play_bot(state, sender).await?;
+            play_bot(state, record, settings, outbox).await?;
         }
         RoomMessageType::End { .. } => {
             tracing::info!("bot task say goodbye.");
@@ -412,7 +1315,7 @@ async fn bot_task(
 
 async fn send_message_after_cards_replaced(
     game: &Game,
-    sender: &Sender<RoomMessage>,
+    outbox: &Outbox,
     next_player_id: Uuid,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     // todo we may need to filter on user that are not bot
@@ -422,49 +1325,25 @@ async fn send_message_after_cards_replaced(
     match &game.state {
         GameState::ExchangeCards { commands: _ } => {
             // send change cards
-            sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: None,
-                    msg_type: RoomMessageType::NextPlayerToReplaceCards {
+            outbox
+                .send(RoomMessage::public(
+                    RoomMessageType::NextPlayerToReplaceCards {
                         current_player_id: next_player_id,
                         uuid,
                     },
-                })
+                ))
                 .await?;
         }
-        GameState::PlayingHand {
-            stack,
-            current_scores: _,
-        } => {
+        GameState::PlayingHand { .. } => {
             // send play event
-            sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: None,
-                    msg_type: RoomMessageType::StartHand {
-                        current_player_id: next_player_id,
-                        uuid,
-                    },
-                })
+            outbox
+                .send(RoomMessage::public(RoomMessageType::StartHand {
+                    current_player_id: next_player_id,
+                    uuid,
+                }))
                 .await?;
-            // for player_id in game.player_ids_in_order() {
-            //     let cards: [Option<PlayerCard>; PLAYER_CARD_SIZE] = game
-            //         .get_player_cards(player_id)
-            //         .map(convert_card_to_player_card);
-            //     sender
-            //         .broadcast_direct(RoomMessage {
-            //             from_user_id: None,
-            //             to_user_id: Some(player_id),
-            //             msg_type: RoomMessageType::NextPlayerToPlay {
-            //                 current_player_id: next_player_id,
-            //                 current_cards: Some(cards),
-            //                 uuid,
-            //                 stack: convert_stack_to_card_player_card(stack),
-            //             },
-            //         })
-            //         .await?;
-            // }
+            // the leader must open with the two of clubs
+            send_legal_moves(game, uuid, outbox).await?;
         }
         any => {
             tracing::warn!("receiving weird event from game after exchange cards: {any:?}");
@@ -474,18 +1353,17 @@ async fn send_message_after_cards_replaced(
 }
 
 async fn send_current_state(
-    state: &RoomState,
+    room: &Room,
     from_user_id: Uuid,
-    sender: &Sender<RoomMessage>,
+    outbox: &Outbox,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    match state {
+    match &room.state {
         RoomState::WaitingForPlayers(ref players_slot) => {
-            sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: Some(from_user_id),
-                    msg_type: RoomMessageType::WaitingForPlayers(*players_slot),
-                })
+            outbox
+                .send(RoomMessage::to(
+                    from_user_id,
+                    RoomMessageType::WaitingForPlayers(*players_slot),
+                ))
                 .await?;
         }
         RoomState::Started(ref players, ref game) | RoomState::Done(ref players, ref game) => {
@@ -510,11 +1388,10 @@ async fn send_current_state(
 
             let current_scores = game.current_score_by_id();
             let player_scores = game.player_score_by_id();
-            sender
-                .broadcast_direct(RoomMessage {
-                    from_user_id: None,
-                    to_user_id: Some(from_user_id),
-                    msg_type: RoomMessageType::State {
+            outbox
+                .send(RoomMessage::to(
+                    from_user_id,
+                    RoomMessageType::State {
                         mode: Cow::Borrowed(state),
                         player_scores,
                         current_scores,
@@ -522,29 +1399,130 @@ async fn send_current_state(
                         current_stack: stack,
                         current_hand: game.current_hand,
                         current_player_id: game.current_player_id(),
+                        legal_moves: legal_moves(game, from_user_id),
+                        tricks: room.record.tricks.clone(),
                         hands: game.hands,
+                        players: players_in_order(players, game),
+                        session: Box::new(room.record.session.clone()),
+                        rematch_votes: room.rematch_votes.iter().copied().collect(),
                     },
-                })
+                ))
                 .await?;
         }
     }
     Ok(())
 }
 
+// deals a new game to the seated players, bots included
+async fn start_game(
+    room: &Arc<RwLock<Room>>,
+    room_guard: &mut Room,
+    users: [User; PLAYER_NUMBER],
+    outbox: &Outbox,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let settings = room_guard.settings;
+    let players: [(UserId, bool); PLAYER_NUMBER] = users.map(|user| (user.id, user.bot));
+
+    let game = Game::new(players, settings.game_hands());
+    let current_player_id = game.current_player_id().ok_or("should not happen")?;
+
+    let player_ids_in_order = game.player_ids_in_order();
+    room_guard.state = RoomState::Started(users, game);
+    room_guard
+        .record
+        .start(room_guard.id, &users, &game, settings);
+
+    // notify game is about to start
+    let player_scores = game.player_score_by_id();
+    let uuid = Uuid::new_v4();
+    outbox
+        .send(RoomMessage::public(RoomMessageType::NewHand {
+            player_ids_in_order,
+            players: players_in_order(&users, &game),
+            player_scores,
+            uuid,
+            current_player_id,
+            current_hand: game.current_hand,
+            hands: game.hands,
+        }))
+        .await?;
+    let timeout_outbox = outbox.clone();
+    let timeout_receiver = timeout_outbox.room_sender.new_receiver();
+    let room_clone = room.clone();
+    tokio::spawn(async move {
+        timeout_bot(
+            room_clone,
+            current_player_id,
+            timeout_receiver,
+            timeout_outbox,
+            settings,
+        )
+        .await
+    });
+    Ok(())
+}
+
+// a room restored from the database may be in the middle of a game.
+// notify the current player again, so bots resume playing, and watch for timeouts
+async fn resume_game(
+    room: &Arc<RwLock<Room>>,
+    outbox: &Outbox,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (current_player_id, msg_type, settings) = {
+        let room_guard = room.read().await;
+        let RoomState::Started(_, ref game) = room_guard.state else {
+            return Ok(());
+        };
+        let current_player_id = game.current_player_id().ok_or("should not happen")?;
+        let uuid = Uuid::new_v4();
+        let msg_type = match &game.state {
+            GameState::ExchangeCards { .. } => RoomMessageType::NextPlayerToReplaceCards {
+                current_player_id,
+                uuid,
+            },
+            GameState::PlayingHand { stack, .. } => RoomMessageType::NextPlayerToPlay {
+                current_player_id,
+                current_cards: None,
+                legal_moves: None,
+                stack: convert_stack_to_card_player_card(stack),
+                uuid,
+            },
+            any => {
+                tracing::warn!("cannot resume game in state {any:?}");
+                return Ok(());
+            }
+        };
+        tracing::info!("resume game in room {}", room_guard.id);
+        (current_player_id, msg_type, room_guard.settings)
+    };
+
+    let timeout_outbox = outbox.clone();
+    let timeout_receiver = timeout_outbox.room_sender.new_receiver();
+    let room_clone = room.clone();
+    tokio::spawn(async move {
+        timeout_bot(
+            room_clone,
+            current_player_id,
+            timeout_receiver,
+            timeout_outbox,
+            settings,
+        )
+        .await
+    });
+    outbox.send(RoomMessage::public(msg_type)).await?;
+    Ok(())
+}
+
 pub async fn room_task(
     room: Arc<RwLock<Room>>,
+    mut receiver: Receiver<RoomMessage>,
     pool: Pool<Sqlite>,
     id: Uuid,
+    rooms: Rooms,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     tracing::info!("setup room task {id}...");
-    let (sender, mut receiver) = {
-        let mut room_guard = room.write().await;
-        let Some(sender) = room_guard.sender.take() else {
-            panic!("no sender")
-        };
-        let receiver = sender.new_receiver();
-        (sender, receiver)
-    };
+    let outbox = room.read().await.outbox.clone();
+    resume_game(&room, &outbox).await?;
     tracing::info!("listening room task {id}...");
     loop {
         match receiver.recv_direct().await {
@@ -554,10 +1532,10 @@ pub async fn room_task(
                      inactive receiver count {}, 
                      sender count {},
                      message in the channel {}",
-                    sender.receiver_count(),
-                    sender.inactive_receiver_count(),
-                    sender.sender_count(),
-                    sender.len()
+                    outbox.room_sender.receiver_count(),
+                    outbox.room_sender.inactive_receiver_count(),
+                    outbox.room_sender.sender_count(),
+                    outbox.room_sender.len()
                 );
 
                 let Some(from_user_id) = msg.from_user_id else {
@@ -574,7 +1552,12 @@ pub async fn room_task(
                     };
                     if is_bot {
                         let mut room_guard = room.write().await;
-                        bot_task(&mut room_guard.state, &sender, msg).await?;
+                        let settings = room_guard.settings;
+                        let rg = &mut *room_guard;
+                        let res =
+                            bot_task(&mut rg.state, &mut rg.record, &settings, &outbox, msg).await;
+                        persist_room(&room_guard).await;
+                        res?;
                     }
 
                     continue;
@@ -585,18 +1568,46 @@ pub async fn room_task(
                         let mut room_guard = room.write().await;
                         let is_viewer = room_guard.viewers.iter().any(|p| p == &from_user_id);
                         let bots = room_guard.bots;
+                        let settings = room_guard.settings;
 
                         match room_guard.state {
                             RoomState::WaitingForPlayers(ref mut players) => {
-                                if players.iter().any(|p| p == &Some(from_user_id)) || is_viewer {
-                                    sender
-                                        .broadcast_direct(RoomMessage {
-                                            from_user_id: None,
-                                            to_user_id: Some(from_user_id),
-                                            msg_type: RoomMessageType::PlayerError(
-                                                GameError::StateError,
+                                if players.iter().flatten().any(|p| p.id == from_user_id)
+                                    || is_viewer
+                                {
+                                    outbox
+                                        .send(RoomMessage::to(
+                                            from_user_id,
+                                            RoomMessageType::PlayerError(
+                                                GameError::StateError.into(),
                                             ),
+                                        ))
+                                        .await?;
+                                    continue;
+                                }
+
+                                let user = if bots.contains(&Some(from_user_id)) {
+                                    User::default().with_id(from_user_id)
+                                } else {
+                                    find_user_by_id(from_user_id, &pool)
+                                        .await
+                                        .ok()
+                                        .unwrap_or_else(|| {
+                                            User::default().with_id(from_user_id).human(true)
                                         })
+                                };
+                                if players
+                                    .iter()
+                                    .flatten()
+                                    .any(|p| p.name.as_str() == user.name.as_str())
+                                {
+                                    outbox
+                                        .send(RoomMessage::to(
+                                            from_user_id,
+                                            RoomMessageType::PlayerError(
+                                                PlayerErrorKind::NameTaken,
+                                            ),
+                                        ))
                                         .await?;
                                     continue;
                                 }
@@ -606,97 +1617,85 @@ pub async fn room_task(
                                     unreachable!()
                                 };
 
-                                *player_slot = Some(from_user_id);
-                                sender
-                                    .broadcast_direct(RoomMessage {
-                                        from_user_id: None,
-                                        to_user_id: None,
-                                        msg_type: RoomMessageType::Joined(from_user_id),
-                                    })
+                                *player_slot = Some(user);
+                                outbox
+                                    .send(RoomMessage::public(RoomMessageType::Joined(user)))
                                     .await?;
 
                                 if players.iter().all(|p| p.is_some()) {
-                                    let users: [User; PLAYER_NUMBER] =
-                                        to_static_array(players, |player| {
-                                            let Some(player) = player else {
-                                                unreachable!("wtf")
-                                            };
-                                            let pool_clone = pool.clone();
-                                            async move {
-                                                Ok(find_user_by_id(player, &pool_clone)
-                                                    .await
-                                                    .ok()
-                                                    .unwrap_or_else(|| {
-                                                        User::default().with_id(player)
-                                                    }))
-                                            }
-                                        })
-                                        .await?;
-
-                                    let players: [(UserId, bool); PLAYER_NUMBER] =
-                                        users.map(|user| (user.id, user.bot));
-
-                                    let game = Game::new(players, DEFAULT_HANDS);
-                                    let current_player_id =
-                                        game.current_player_id().ok_or("should not happen")?;
-
-                                    let player_ids_in_order = game.player_ids_in_order();
-                                    room_guard.state = RoomState::Started(users, game);
-
-                                    // notify game is about to start
-                                    let player_scores = game.player_score_by_id();
-                                    let uuid = Uuid::new_v4();
-                                    sender
-                                        .broadcast_direct(RoomMessage {
-                                            from_user_id: None,
-                                            to_user_id: None,
-                                            msg_type: RoomMessageType::NewHand {
-                                                player_ids_in_order,
-                                                player_scores,
-                                                uuid,
-                                                current_player_id,
-                                                current_hand: game.current_hand,
-                                                hands: game.hands,
-                                            },
-                                        })
-                                        .await?;
-                                    let timeout_sender = sender.clone();
-                                    let timeout_receiver = timeout_sender.new_receiver();
-                                    let room_clone = room.clone();
-                                    tokio::spawn(async move {
-                                        timeout_bot(
-                                            room_clone,
-                                            current_player_id,
-                                            timeout_receiver,
-                                            timeout_sender,
-                                        )
-                                        .await
+                                    let users: [User; PLAYER_NUMBER] = players.map(|player| {
+                                        let Some(player) = player else {
+                                            unreachable!("wtf")
+                                        };
+                                        player
                                     });
+                                    start_game(&room, &mut room_guard, users, &outbox).await?;
                                 }
                             }
                             RoomState::Started(ref users, _) | RoomState::Done(ref users, _) => {
                                 if users.iter().any(|u| u.id == from_user_id) {
-                                    sender
-                                        .broadcast_direct(RoomMessage {
-                                            from_user_id: None,
-                                            to_user_id: Some(from_user_id),
-                                            msg_type: RoomMessageType::PlayerError(
-                                                GameError::StateError,
+                                    outbox
+                                        .send(RoomMessage::to(
+                                            from_user_id,
+                                            RoomMessageType::PlayerError(
+                                                GameError::StateError.into(),
                                             ),
-                                        })
+                                        ))
                                         .await?;
                                 } else {
                                     room_guard.viewers.insert(from_user_id);
-                                    sender
-                                        .broadcast_direct(RoomMessage {
-                                            from_user_id: None,
-                                            to_user_id: None,
-                                            msg_type: RoomMessageType::ViewerJoined(from_user_id),
-                                        })
+                                    outbox
+                                        .send(RoomMessage::public(RoomMessageType::ViewerJoined(
+                                            from_user_id,
+                                        )))
                                         .await?;
                                 }
                             }
                         }
+                        persist_room(&room_guard).await;
+                    }
+                    msg_type @ (RoomMessageType::RequestRematch
+                    | RoomMessageType::AcceptRematch) => {
+                        let mut room_guard = room.write().await;
+                        let RoomState::Done(users, _) = room_guard.state else {
+                            outbox
+                                .send(RoomMessage::to(
+                                    from_user_id,
+                                    RoomMessageType::PlayerError(GameError::StateError.into()),
+                                ))
+                                .await?;
+                            continue;
+                        };
+                        // only seated humans vote, and someone must have asked first
+                        let is_player = users.iter().any(|u| u.id == from_user_id && !u.bot);
+                        if !is_player
+                            || (msg_type == RoomMessageType::AcceptRematch
+                                && room_guard.rematch_votes.is_empty())
+                        {
+                            outbox
+                                .send(RoomMessage::to(
+                                    from_user_id,
+                                    RoomMessageType::PlayerError(GameError::StateError.into()),
+                                ))
+                                .await?;
+                            continue;
+                        }
+                        room_guard.rematch_votes.insert(from_user_id);
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::RematchVotes(
+                                room_guard.rematch_votes.iter().copied().collect(),
+                            )))
+                            .await?;
+                        if users
+                            .iter()
+                            .filter(|u| !u.bot)
+                            .all(|u| room_guard.rematch_votes.contains(&u.id))
+                        {
+                            room_guard.rematch_votes.clear();
+                            start_game(&room, &mut room_guard, users, &outbox).await?;
+                            tracing::info!("rematch in room {id}");
+                        }
+                        persist_room(&room_guard).await;
                     }
                     RoomMessageType::GetCards => {
                         let room_guard = room.read().await;
@@ -707,79 +1706,238 @@ pub async fn room_task(
                             let cards: [Option<PlayerCard>; PLAYER_CARD_SIZE] = game
                                 .get_player_cards(from_user_id)
                                 .map(convert_card_to_player_card);
-                            sender
-                                .broadcast_direct(RoomMessage {
-                                    from_user_id: None,
-                                    to_user_id: Some(from_user_id),
-                                    msg_type: RoomMessageType::ReceiveCards(cards),
-                                })
+                            outbox
+                                .send(RoomMessage::to(
+                                    from_user_id,
+                                    RoomMessageType::ReceiveCards {
+                                        cards,
+                                        legal_moves: legal_moves(game, from_user_id),
+                                    },
+                                ))
                                 .await?;
                         } else {
                             tracing::debug!("should not happen")
                         }
                     }
 
+                    // players and viewers can review the tricks of the hand
+                    RoomMessageType::GetTrickHistory => {
+                        let room_guard = room.read().await;
+                        outbox
+                            .send(RoomMessage::to(
+                                from_user_id,
+                                RoomMessageType::TrickHistory(room_guard.record.tricks.clone()),
+                            ))
+                            .await?;
+                    }
                     RoomMessageType::ReplaceCards(player_cards_exchange) => {
                         let mut room_guard = room.write().await;
                         if !is_valid_msg(&room_guard, from_user_id) {
                             continue;
                         }
-                        if let RoomState::Started(ref players, ref mut game) = room_guard.state {
+                        let rg = &mut *room_guard;
+                        if let RoomState::Started(ref players, ref mut game) = rg.state {
                             if game.current_player_id() == Some(from_user_id) {
                                 if let GameState::ExchangeCards { commands: _ } = &game.state {
                                     let command =
                                         player_cards_exchange.map(|pc| pc.position_in_deck);
                                     if let Err(game_error) = game.exchange_cards(command) {
-                                        sender
-                                            .broadcast_direct(RoomMessage {
-                                                from_user_id: None,
-                                                to_user_id: Some(from_user_id),
-                                                msg_type: RoomMessageType::PlayerError(game_error),
-                                            })
+                                        outbox
+                                            .send(RoomMessage::to(
+                                                from_user_id,
+                                                RoomMessageType::PlayerError(game_error.into()),
+                                            ))
                                             .await?;
                                     } else {
+                                        rg.record.exchanged(game, from_user_id, command);
                                         let Some(next_player_id) = game.current_player_id() else {
                                             unreachable!()
                                         };
                                         send_message_after_cards_replaced(
                                             game,
-                                            &sender,
+                                            &outbox,
                                             next_player_id,
                                         )
                                         .await?;
+                                        persist_room(&room_guard).await;
                                     }
                                 }
                             }
                         }
                     }
                     RoomMessageType::JoinBot => {
-                        // todo make sure the room creator is the one who send the msg
                         let mut room_guard = room.write().await;
+                        if !is_owner(&room_guard, from_user_id) {
+                            send_player_error(&outbox, from_user_id, PlayerErrorKind::NotOwner)
+                                .await?;
+                            continue;
+                        }
 
                         if let RoomState::WaitingForPlayers(ref players) = &room_guard.state {
                             let uuid = Uuid::new_v4();
                             let Some(bot_seat) = room_guard.bots.iter_mut().find(|b| b.is_none())
                             else {
-                                unreachable!("no seats for bot")
+                                send_player_error(
+                                    &outbox,
+                                    from_user_id,
+                                    GameError::StateError.into(),
+                                )
+                                .await?;
+                                continue;
                             };
                             *bot_seat = Some(uuid);
+                            persist_room(&room_guard).await;
 
-                            sender
-                                .broadcast_direct(RoomMessage {
-                                    from_user_id: Some(uuid),
-                                    to_user_id: None,
-                                    msg_type: RoomMessageType::Join,
-                                })
+                            outbox
+                                .send_to_room(RoomMessage::from_user(uuid, RoomMessageType::Join))
+                                .await?;
+                        }
+                    }
+                    msg_type @ (RoomMessageType::LeaveSeat | RoomMessageType::Leave) => {
+                        let mut room_guard = room.write().await;
+                        let rg = &mut *room_guard;
+                        let left_seat = rg.state.free_seat(from_user_id);
+                        let left_viewers =
+                            msg_type == RoomMessageType::Leave && rg.viewers.remove(&from_user_id);
+                        if left_viewers {
+                            if let Err(e) = delete_room_viewer(rg.id, from_user_id, &pool)
+                                .await
+                                .map_err(|e| e.to_string())
+                            {
+                                tracing::error!("could not remove viewer {from_user_id}: {e}");
+                            }
+                        }
+                        if !left_seat && !left_viewers {
+                            // seats cannot be released once the game started
+                            send_player_error(&outbox, from_user_id, GameError::StateError.into())
+                                .await?;
+                            continue;
+                        }
+                        persist_room(&room_guard).await;
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::Left(from_user_id)))
+                            .await?;
+                    }
+                    RoomMessageType::RemoveBot(bot_id) => {
+                        let mut room_guard = room.write().await;
+                        if !is_owner(&room_guard, from_user_id) {
+                            send_player_error(&outbox, from_user_id, PlayerErrorKind::NotOwner)
+                                .await?;
+                            continue;
+                        }
+                        let rg = &mut *room_guard;
+                        let Some(bot_slot) = rg.bots.iter_mut().find(|b| **b == Some(bot_id))
+                        else {
+                            send_player_error(
+                                &outbox,
+                                from_user_id,
+                                PlayerErrorKind::UnknownUser(bot_id),
+                            )
+                            .await?;
+                            continue;
+                        };
+                        if !rg.state.free_seat(bot_id) {
+                            send_player_error(&outbox, from_user_id, GameError::StateError.into())
+                                .await?;
+                            continue;
+                        }
+                        *bot_slot = None;
+                        persist_room(&room_guard).await;
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::Kicked(bot_id)))
+                            .await?;
+                    }
+                    RoomMessageType::Kick(user_id) => {
+                        let mut room_guard = room.write().await;
+                        if !is_owner(&room_guard, from_user_id) {
+                            send_player_error(&outbox, from_user_id, PlayerErrorKind::NotOwner)
                                 .await?;
+                            continue;
                         }
+                        let rg = &mut *room_guard;
+                        if rg.viewers.remove(&user_id) {
+                            if let Err(e) = delete_room_viewer(rg.id, user_id, &pool)
+                                .await
+                                .map_err(|e| e.to_string())
+                            {
+                                tracing::error!("could not remove viewer {user_id}: {e}");
+                            }
+                        } else if rg.state.free_seat(user_id) {
+                            for bot in rg.bots.iter_mut().filter(|b| **b == Some(user_id)) {
+                                *bot = None;
+                            }
+                        } else {
+                            // seated players cannot be kicked once the game started
+                            let is_seated =
+                                rg.state.seats().iter().flatten().any(|u| u.id == user_id);
+                            let kind = if is_seated {
+                                GameError::StateError.into()
+                            } else {
+                                PlayerErrorKind::UnknownUser(user_id)
+                            };
+                            send_player_error(&outbox, from_user_id, kind).await?;
+                            continue;
+                        }
+                        persist_room(&room_guard).await;
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::Kicked(user_id)))
+                            .await?;
+                    }
+                    RoomMessageType::TransferOwnership(new_owner) => {
+                        let mut room_guard = room.write().await;
+                        if !is_owner(&room_guard, from_user_id) {
+                            send_player_error(&outbox, from_user_id, PlayerErrorKind::NotOwner)
+                                .await?;
+                            continue;
+                        }
+                        let is_human_member = room_guard.viewers.contains(&new_owner)
+                            || room_guard
+                                .state
+                                .seats()
+                                .iter()
+                                .flatten()
+                                .any(|u| u.id == new_owner && !u.bot);
+                        if !is_human_member {
+                            send_player_error(
+                                &outbox,
+                                from_user_id,
+                                PlayerErrorKind::UnknownUser(new_owner),
+                            )
+                            .await?;
+                            continue;
+                        }
+                        room_guard.owner = Some(new_owner);
+                        persist_room(&room_guard).await;
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::OwnerChanged(
+                                new_owner,
+                            )))
+                            .await?;
+                    }
+                    RoomMessageType::CloseRoom => {
+                        // don't hold the room lock while removing it from the map
+                        if !is_owner(&*room.read().await, from_user_id) {
+                            send_player_error(&outbox, from_user_id, PlayerErrorKind::NotOwner)
+                                .await?;
+                            continue;
+                        }
+                        outbox.close()?;
+                        rooms.remove(&id);
+                        room.read().await.lobby.remove(id);
+                        if let Err(e) = delete_room(id, &pool).await.map_err(|e| e.to_string()) {
+                            tracing::error!("could not delete room {id}: {e}");
+                        }
+                        tracing::info!("room {id} closed by {from_user_id}");
+                        return Ok(());
                     }
                     RoomMessageType::Play(player_card) => {
                         let mut room_guard = room.write().await;
+                        let settings = room_guard.settings;
                         if !is_valid_msg(&room_guard, from_user_id) {
                             continue;
                         }
-                        if let RoomState::Started(ref mut players, ref mut game) = room_guard.state
-                        {
+                        let rg = &mut *room_guard;
+                        if let RoomState::Started(ref mut players, ref mut game) = rg.state {
                             if game.current_player_id() == Some(from_user_id) {
                                 if let GameState::PlayingHand {
                                     stack: _,
@@ -788,32 +1946,184 @@ pub async fn room_task(
                                 {
                                     if let Err(game_error) = game.play(player_card.position_in_deck)
                                     {
-                                        sender
-                                            .broadcast_direct(RoomMessage {
-                                                from_user_id: None,
-                                                to_user_id: Some(from_user_id),
-                                                msg_type: RoomMessageType::PlayerError(game_error),
-                                            })
+                                        outbox
+                                            .send(RoomMessage::to(
+                                                from_user_id,
+                                                RoomMessageType::PlayerError(game_error.into()),
+                                            ))
                                             .await?;
-                                    } else if send_message_after_played(game, &sender).await? {
+                                    } else if send_message_after_played(
+                                        game,
+                                        &mut rg.record,
+                                        players,
+                                        &settings,
+                                        &outbox,
+                                    )
+                                    .await?
+                                    {
                                         // game is done, update state
-                                        let player_scores = game.player_score_by_id();
-                                        room_guard.state = RoomState::Done(*players, *game);
-                                        sender
-                                            .broadcast_direct(RoomMessage {
-                                                from_user_id: None,
-                                                to_user_id: None,
-                                                msg_type: RoomMessageType::End { player_scores },
-                                            })
-                                            .await?;
+                                        rg.record.ended(game);
+                                        let msg_type = end_message(game, &rg.record);
+                                        rg.state = RoomState::Done(*players, *game);
+                                        persist_room(&room_guard).await;
+                                        outbox.send(RoomMessage::public(msg_type)).await?;
+                                    } else {
+                                        persist_room(&room_guard).await;
                                     }
                                 }
                             }
                         }
                     }
-                    RoomMessageType::GetCurrentState => {
+                    RoomMessageType::SetName(name) => {
+                        let mut room_guard = room.write().await;
+                        let name = match validate_name(&name) {
+                            Ok(name) => name,
+                            Err(e) => {
+                                outbox
+                                    .send(RoomMessage::to(
+                                        from_user_id,
+                                        RoomMessageType::PlayerError(PlayerErrorKind::InvalidName(
+                                            Cow::Borrowed(e),
+                                        )),
+                                    ))
+                                    .await?;
+                                continue;
+                            }
+                        };
+                        // registered names are unique, other names only within the room
+                        let registered_by_other = find_credentials_by_name(&name, &pool)
+                            .await
+                            .ok()
+                            .flatten()
+                            .is_some_and(|(id, _)| id != from_user_id);
+                        if registered_by_other
+                            || room_guard.state.is_name_taken(from_user_id, &name)
+                        {
+                            outbox
+                                .send(RoomMessage::to(
+                                    from_user_id,
+                                    RoomMessageType::PlayerError(PlayerErrorKind::NameTaken),
+                                ))
+                                .await?;
+                            continue;
+                        }
+                        let Some(user) = find_user_by_id(from_user_id, &pool).await.ok() else {
+                            tracing::warn!("unknown user {from_user_id} cannot change name");
+                            continue;
+                        };
+                        let user = User { name, ..user };
+                        if let Err(e) = upsert_user(user, &pool).await.map_err(|e| e.to_string()) {
+                            tracing::error!("could not save name of {from_user_id}: {e}");
+                            continue;
+                        }
+                        room_guard.state.update_user(user);
+                        persist_room(&room_guard).await;
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::NameChanged(user)))
+                            .await?;
+                    }
+                    msg_type @ (RoomMessageType::GetCurrentState
+                    | RoomMessageType::Resume { .. }) => {
+                        if let RoomMessageType::Resume { last_seq } = msg_type {
+                            if outbox.replay(from_user_id, last_seq) {
+                                continue;
+                            }
+                            tracing::debug!(
+                                "{from_user_id} missed too many events since {last_seq}"
+                            );
+                        }
                         let room_guard = room.read().await;
-                        send_current_state(&room_guard.state, from_user_id, &sender).await?;
+                        send_current_state(&room_guard, from_user_id, &outbox).await?;
+                        outbox
+                            .send(RoomMessage::to(
+                                from_user_id,
+                                RoomMessageType::ConnectedPlayers(
+                                    room_guard.connections.keys().copied().collect(),
+                                ),
+                            ))
+                            .await?;
+                    }
+                    RoomMessageType::Connected => {
+                        let mut room_guard = room.write().await;
+                        if room_guard.connect(from_user_id) {
+                            outbox
+                                .send(RoomMessage::public(RoomMessageType::PlayerConnected(
+                                    from_user_id,
+                                )))
+                                .await?;
+                        }
+                        if !room_guard.reclaim_seat(from_user_id) {
+                            continue;
+                        }
+                        persist_room(&room_guard).await;
+                        tracing::info!("{from_user_id} reclaimed its seat");
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::SeatReclaimed(
+                                from_user_id,
+                            )))
+                            .await?;
+                        send_current_state(&room_guard, from_user_id, &outbox).await?;
+                    }
+                    RoomMessageType::Disconnected => {
+                        let mut room_guard = room.write().await;
+                        if !room_guard.disconnect(from_user_id, Instant::now()) {
+                            continue;
+                        }
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::PlayerDisconnected(
+                                from_user_id,
+                            )))
+                            .await?;
+                        if room_guard.disconnected_since.contains_key(&from_user_id) {
+                            let grace_outbox = outbox.clone();
+                            tokio::spawn(async move {
+                                tokio::time::sleep(Duration::from_secs(DISCONNECT_GRACE_SECS))
+                                    .await;
+                                // the room was closed or reaped meanwhile
+                                if grace_outbox.room_sender.is_closed() {
+                                    return;
+                                }
+                                if let Err(e) = grace_outbox
+                                    .send_to_room(RoomMessage::from_user(
+                                        from_user_id,
+                                        RoomMessageType::TakeOverSeat,
+                                    ))
+                                    .await
+                                {
+                                    tracing::error!("could not end grace period {e:?}");
+                                }
+                            });
+                        }
+                    }
+                    RoomMessageType::TakeOverSeat => {
+                        let mut room_guard = room.write().await;
+                        if !room_guard.take_over_seat(from_user_id, Instant::now()) {
+                            continue;
+                        }
+                        persist_room(&room_guard).await;
+                        tracing::info!("bot takes over the seat of {from_user_id}");
+                        outbox
+                            .send(RoomMessage::public(RoomMessageType::BotTakeover(
+                                from_user_id,
+                            )))
+                            .await?;
+
+                        // otherwise the bot plays on the next turn notification
+                        let is_current_player = matches!(
+                            room_guard.state,
+                            RoomState::Started(_, ref game)
+                                if game.current_player_id() == Some(from_user_id)
+                        );
+                        if is_current_player {
+                            let settings = room_guard.settings;
+                            let rg = &mut *room_guard;
+                            let res =
+                                play_bot(&mut rg.state, &mut rg.record, &settings, &outbox).await;
+                            persist_room(&room_guard).await;
+                            if let Err(e) = res {
+                                tracing::error!("bot could not take over {from_user_id}: {e}");
+                            }
+                        }
                     }
                     e => {
                         tracing::warn!("received {e:?}. should not happen");
@@ -832,13 +2142,42 @@ pub async fn room_task(
 #[cfg(test)]
 mod test {
 
-    use std::str::FromStr;
+    use std::{
+        str::FromStr,
+        sync::Arc,
+        time::{Duration, Instant},
+    };
 
+    use dashmap::DashMap;
+    use futures::StreamExt;
+    use tokio::sync::RwLock;
     use uuid::Uuid;
 
-    use crate::room::RoomMessage;
+    use crate::{db::test_pool, room::RoomMessage};
 
-    use super::User;
+    use lib_hearts::{get_card_by_idx, Game, GameError, PlayerState, CARD_TO_START, PLAYER_NUMBER};
+
+    use super::{
+        completed_trick, is_owner, legal_moves, send_message_after_cards_replaced, timeout_bot,
+        winners, EndCondition, Lobby, LobbyUpdate, Outbox, PlayerErrorKind, Room, RoomMessageType,
+        RoomSettings, RoomState, RoomStatus, RoomSummary, SessionScores, User, UserId,
+        DISCONNECT_GRACE_SECS, EVENT_LOG_SIZE, LOBBY_CHANNEL_CAPACITY,
+    };
+
+    // a running room on an in memory database
+    async fn test_room(owner: UserId) -> Arc<RwLock<Room>> {
+        let rooms = Arc::new(DashMap::new());
+        let (id, room) = Room::new(
+            test_pool().await,
+            rooms.clone(),
+            Lobby::new(),
+            RoomSettings::default(),
+            owner,
+        )
+        .await;
+        rooms.insert(id, room.clone());
+        room
+    }
 
     #[test]
     fn test_serializ_user() {
@@ -853,6 +2192,7 @@ mod test {
             RoomMessage {
                 from_user_id: Uuid::from_str("96f6b528-4fdc-47ed-8c50-277b13587fc1").ok(),
                 to_user_id: None,
+                seq: None,
                 msg_type: crate::room::RoomMessageType::Join
             },
             serde_json::from_str::<RoomMessage>(
@@ -867,6 +2207,492 @@ mod test {
             .unwrap()
         );
     }
+    #[test]
+    fn test_room_settings() {
+        let settings: RoomSettings = serde_json::from_str(r#"{"hands": 5}"#).unwrap();
+        assert_eq!(
+            RoomSettings {
+                hands: 5,
+                ..Default::default()
+            },
+            settings
+        );
+        assert!(settings.validate().is_ok());
+        assert!(RoomSettings {
+            hands: 0,
+            ..settings
+        }
+        .validate()
+        .is_err());
+    }
+    #[test]
+    fn test_winners() {
+        let scores = [3, 26, 3, 14].map(|score| PlayerState {
+            player_id: Uuid::new_v4(),
+            score,
+        });
+        assert_eq!(
+            vec![scores[0].player_id, scores[2].player_id],
+            winners(&scores)
+        );
+        let settings = RoomSettings {
+            end_condition: EndCondition::ScoreThreshold,
+            score_threshold: 0,
+            ..Default::default()
+        };
+        assert!(settings.validate().is_err());
+    }
+    #[test]
+    fn test_session_scores() {
+        let ids = [(); PLAYER_NUMBER].map(|_| Uuid::new_v4());
+        let mut session = SessionScores::default();
+        session.add(&ids.map(|player_id| PlayerState {
+            player_id,
+            score: 10,
+        }));
+        // players are matched by id, not by position
+        let mut scores: Vec<_> = ids
+            .iter()
+            .zip([3, 2, 1, 0])
+            .map(|(player_id, score)| PlayerState {
+                player_id: *player_id,
+                score,
+            })
+            .collect();
+        scores.reverse();
+        session.add(&scores);
+        assert_eq!(2, session.matches);
+        assert_eq!(
+            ids.iter()
+                .zip([13, 12, 11, 10])
+                .map(|(player_id, score)| PlayerState {
+                    player_id: *player_id,
+                    score,
+                })
+                .collect::<Vec<_>>(),
+            session.player_scores
+        );
+    }
+    #[test]
+    fn test_completed_trick() {
+        let game = Game::new([(); PLAYER_NUMBER].map(|_| (Uuid::new_v4(), false)), 1);
+        let player_ids = game.player_ids_in_order();
+        // the third player led
+        let stack = [Some((2, 0)), Some((3, 50)), Some((0, 24)), Some((1, 51))];
+        let trick = completed_trick(&stack, &game, player_ids[3]).unwrap();
+        assert_eq!(player_ids[2], trick.leader);
+        assert_eq!(player_ids[3], trick.winner);
+        assert_eq!(
+            vec![player_ids[2], player_ids[3], player_ids[0], player_ids[1]],
+            trick.cards.iter().map(|c| c.player_id).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![0, 50, 24, 51],
+            trick
+                .cards
+                .iter()
+                .map(|c| c.card.position_in_deck)
+                .collect::<Vec<_>>()
+        );
+        let points: usize = [0, 50, 24, 51]
+            .map(|idx| get_card_by_idx(idx).get_value())
+            .iter()
+            .sum();
+        assert_eq!(points, trick.points);
+
+        assert!(completed_trick(&[None; PLAYER_NUMBER], &game, player_ids[0]).is_none());
+    }
+    #[test]
+    fn test_legal_moves() {
+        let mut game = Game::new([(); PLAYER_NUMBER].map(|_| (Uuid::new_v4(), false)), 1);
+        let player_ids = game.player_ids_in_order();
+        assert!(player_ids
+            .iter()
+            .all(|id| legal_moves(&game, *id).is_none()));
+
+        for _ in 0..PLAYER_NUMBER {
+            let current_player_id = game.current_player_id().unwrap();
+            let cards = game.get_player_cards(current_player_id);
+            game.exchange_cards([0, 1, 2].map(|i| cards[i].unwrap().0))
+                .unwrap();
+        }
+
+        // whoever holds the two of clubs must start with it
+        let current_player_id = game.current_player_id().unwrap();
+        let start = game
+            .get_player_cards(current_player_id)
+            .into_iter()
+            .flatten()
+            .find(|(_, card)| **card == CARD_TO_START)
+            .map(|(position_in_deck, _)| position_in_deck)
+            .unwrap();
+        assert_eq!(Some(vec![start]), legal_moves(&game, current_player_id));
+        for player_id in player_ids.iter().filter(|id| **id != current_player_id) {
+            assert_eq!(None, legal_moves(&game, *player_id));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_leader_legal_moves_after_exchange() {
+        let mut game = Game::new([(); PLAYER_NUMBER].map(|_| (Uuid::new_v4(), false)), 1);
+        for _ in 0..PLAYER_NUMBER {
+            let current_player_id = game.current_player_id().unwrap();
+            let cards = game.get_player_cards(current_player_id);
+            game.exchange_cards([0, 1, 2].map(|i| cards[i].unwrap().0))
+                .unwrap();
+        }
+        let leader = game.current_player_id().unwrap();
+        let outbox = Outbox::new();
+        let mut leader_receiver = outbox.register(leader);
+        let _room_receiver = outbox.room_sender.new_receiver();
+
+        send_message_after_cards_replaced(&game, &outbox, leader)
+            .await
+            .unwrap();
+        let Some(RoomMessage {
+            msg_type: RoomMessageType::NextPlayerToPlay { legal_moves, .. },
+            ..
+        }) = leader_receiver.try_recv().ok()
+        else {
+            panic!("the leader got no legal moves");
+        };
+        let two_of_clubs = game
+            .get_player_cards(leader)
+            .into_iter()
+            .flatten()
+            .find(|(_, card)| **card == CARD_TO_START)
+            .map(|(position_in_deck, _)| position_in_deck)
+            .unwrap();
+        assert_eq!(Some(vec![two_of_clubs]), legal_moves);
+    }
+
+    #[tokio::test]
+    async fn test_outbox_private_messages() {
+        let outbox = Outbox::new();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut alice_receiver = outbox.register(alice);
+        let mut bob_receiver = outbox.register(bob);
+        let mut public_receiver = outbox.subscribe();
+        // keeps the room channel open, like the room task does
+        let _room_receiver = outbox.room_sender.new_receiver();
+
+        outbox
+            .send(RoomMessage::to(
+                alice,
+                RoomMessageType::ReceiveCards {
+                    cards: [None; 13],
+                    legal_moves: None,
+                },
+            ))
+            .await
+            .unwrap();
+        outbox
+            .send(RoomMessage::public(RoomMessageType::RoomClosed))
+            .await
+            .unwrap();
+
+        let msg = alice_receiver.try_recv().unwrap();
+        assert_eq!(Some(alice), msg.to_user_id);
+        assert!(bob_receiver.try_recv().is_err());
+        let msg = public_receiver.try_recv().unwrap();
+        assert_eq!(RoomMessageType::RoomClosed, msg.msg_type);
+        assert!(public_receiver.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_close() {
+        let outbox = Outbox::new();
+        let mut public_receiver = outbox.subscribe();
+        let mut timeout_receiver = outbox.room_sender.new_receiver();
+        outbox.close().unwrap();
+        // closing twice is harmless
+        outbox.close().unwrap();
+
+        assert_eq!(Some(1), public_receiver.try_recv().unwrap().seq);
+        let msg = public_receiver.try_recv().unwrap();
+        assert_eq!(Some(2), msg.seq);
+        assert_eq!(RoomMessageType::RoomClosed, msg.msg_type);
+        assert_eq!(
+            RoomMessageType::RoomClosed,
+            timeout_receiver.try_recv().unwrap().msg_type
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_to_closed_room() {
+        let msg = RoomMessage::from_user(Uuid::new_v4(), RoomMessageType::Disconnected);
+        // the room task is not listening
+        let outbox = Outbox::new();
+        let sent = tokio::time::timeout(Duration::from_secs(1), outbox.send_to_room(msg.clone()));
+        assert!(sent.await.expect("send to a stopped room hangs").is_err());
+
+        let _room_receiver = outbox.room_sender.new_receiver();
+        outbox.close().unwrap();
+        let sent = tokio::time::timeout(Duration::from_secs(1), outbox.send_to_room(msg));
+        assert!(sent.await.expect("send to a closed room hangs").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_timeout_bot_stops_with_the_room() {
+        let room = test_room(Uuid::new_v4()).await;
+        let outbox = room.read().await.outbox.clone();
+        let receiver = outbox.room_sender.new_receiver();
+        // the channel closed without a RoomClosed, e.g. it was full
+        outbox.room_sender.close();
+        let settings = RoomSettings {
+            timeout_secs: 300,
+            ..Default::default()
+        };
+        let bot = timeout_bot(room, Uuid::new_v4(), receiver, outbox, settings);
+        let stopped = tokio::time::timeout(Duration::from_secs(1), bot).await;
+        assert!(stopped.expect("timeout bot still waits").is_ok());
+    }
+
+    // a started game of four humans
+    async fn started_room() -> (Arc<RwLock<Room>>, [User; PLAYER_NUMBER]) {
+        let players = [(); PLAYER_NUMBER].map(|_| User {
+            id: Uuid::new_v4(),
+            bot: false,
+            ..Default::default()
+        });
+        let room = test_room(players[0].id).await;
+        let game = Game::new(players.map(|p| (p.id, false)), 1);
+        room.write().await.state = RoomState::Started(players, game);
+        (room, players)
+    }
+
+    #[tokio::test]
+    async fn test_take_over_seat() {
+        let (room, [alice, ..]) = started_room().await;
+        let mut room = room.write().await;
+        let now = Instant::now();
+        let grace = Duration::from_secs(DISCONNECT_GRACE_SECS);
+        assert!(room.connect(alice.id));
+        assert!(!room.connect(alice.id));
+        // another tab is still open
+        assert!(!room.disconnect(alice.id, now));
+        assert!(room.disconnect(alice.id, now));
+
+        assert!(!room.take_over_seat(alice.id, now + grace / 2));
+        assert!(room.take_over_seat(alice.id, now + grace));
+        assert!(room.bots.contains(&Some(alice.id)));
+        assert!(!room.take_over_seat(alice.id, now + grace));
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_within_grace() {
+        let (room, [alice, ..]) = started_room().await;
+        let mut room = room.write().await;
+        let now = Instant::now();
+        room.connect(alice.id);
+        room.disconnect(alice.id, now);
+        room.connect(alice.id);
+
+        assert!(!room.take_over_seat(alice.id, now + Duration::from_secs(DISCONNECT_GRACE_SECS)));
+        assert_eq!([None; PLAYER_NUMBER], room.bots);
+    }
+
+    #[tokio::test]
+    async fn test_reclaim_seat() {
+        let (room, [alice, bob, ..]) = started_room().await;
+        let mut room = room.write().await;
+        let now = Instant::now();
+        room.connect(alice.id);
+        room.disconnect(alice.id, now);
+        room.take_over_seat(alice.id, now + Duration::from_secs(DISCONNECT_GRACE_SECS));
+
+        assert!(!room.reclaim_seat(bob.id));
+        assert!(room.reclaim_seat(alice.id));
+        assert_eq!([None; PLAYER_NUMBER], room.bots);
+        assert!(!room.reclaim_seat(alice.id));
+    }
+
+    #[tokio::test]
+    async fn test_is_owner() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let room = test_room(alice).await;
+        let mut room = room.write().await;
+        assert!(is_owner(&room, alice));
+        assert!(!is_owner(&room, bob));
+        // rooms from before the owners
+        room.owner = None;
+        assert!(is_owner(&room, bob));
+    }
+
+    #[tokio::test]
+    async fn test_kick() {
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        let room = test_room(alice).await;
+        let outbox = room.read().await.outbox.clone();
+        let mut alice_receiver = outbox.register(alice);
+        let mut bob_receiver = outbox.register(bob);
+        let mut public_receiver = outbox.subscribe();
+        let seated = User {
+            id: Uuid::new_v4(),
+            bot: false,
+            ..Default::default()
+        };
+        room.write().await.state = RoomState::WaitingForPlayers([Some(seated), None, None, None]);
+        let kick = |from, user_id| {
+            outbox.send_to_room(RoomMessage::from_user(from, RoomMessageType::Kick(user_id)))
+        };
+        let next = |msg: Option<RoomMessage>| msg.expect("channel closed").msg_type;
+
+        kick(bob, seated.id).await.unwrap();
+        assert_eq!(
+            RoomMessageType::PlayerError(PlayerErrorKind::NotOwner),
+            next(bob_receiver.recv().await)
+        );
+        kick(alice, bob).await.unwrap();
+        assert_eq!(
+            RoomMessageType::PlayerError(PlayerErrorKind::UnknownUser(bob)),
+            next(alice_receiver.recv().await)
+        );
+
+        // seats are freed while waiting for players
+        kick(alice, seated.id).await.unwrap();
+        assert_eq!(
+            RoomMessageType::Kicked(seated.id),
+            public_receiver.recv().await.unwrap().msg_type
+        );
+        assert_eq!([None; PLAYER_NUMBER], room.read().await.state.seats());
+
+        // but not once the game started
+        let (started, players) = started_room().await;
+        let started_outbox = started.read().await.outbox.clone();
+        let mut alice_receiver = started_outbox.register(players[0].id);
+        started_outbox
+            .send_to_room(RoomMessage::from_user(
+                players[0].id,
+                RoomMessageType::Kick(players[1].id),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(
+            RoomMessageType::PlayerError(GameError::StateError.into()),
+            next(alice_receiver.recv().await)
+        );
+    }
+
+    #[test]
+    fn test_free_seat() {
+        let user = User {
+            id: Uuid::new_v4(),
+            bot: false,
+            ..Default::default()
+        };
+        let mut waiting = RoomState::WaitingForPlayers([None, Some(user), None, None]);
+        assert!(!waiting.free_seat(Uuid::new_v4()));
+        assert!(waiting.free_seat(user.id));
+        assert_eq!([None; PLAYER_NUMBER], waiting.seats());
+        assert!(!waiting.free_seat(user.id));
+
+        // seats are kept once the game started
+        let players = [user, User::default(), User::default(), User::default()];
+        let game = Game::new(players.map(|p| (p.id, p.bot)), 1);
+        let mut started = RoomState::Started(players, game);
+        assert!(!started.free_seat(user.id));
+        assert_eq!(players.map(Some), started.seats());
+    }
+
+    #[tokio::test]
+    async fn test_lobby_feed_overflow() {
+        let lobby = Lobby::new();
+        let mut feed = Box::pin(lobby.feed());
+        let rooms: Vec<RoomSummary> = (0..=LOBBY_CHANNEL_CAPACITY)
+            .map(|_| RoomSummary {
+                id: Uuid::new_v4(),
+                status: RoomStatus::Waiting,
+                seats: [None; PLAYER_NUMBER],
+                viewers: 0,
+                settings: RoomSettings::default(),
+            })
+            .collect();
+        for room in &rooms {
+            lobby.publish(room.clone());
+        }
+
+        // the oldest update was lost, the page gets every room again
+        let Some(LobbyUpdate::Reset(reset)) = feed.next().await else {
+            panic!("expected a reset");
+        };
+        assert_eq!(lobby.rooms(), reset);
+        assert_eq!(rooms.len(), reset.len());
+        assert_eq!(Some(LobbyUpdate::Room(rooms[1].clone())), feed.next().await);
+    }
+
+    #[test]
+    fn test_lobby() {
+        let lobby = Lobby::new();
+        let summary = |status| RoomSummary {
+            id: Uuid::new_v4(),
+            status,
+            seats: [None; PLAYER_NUMBER],
+            viewers: 0,
+            settings: RoomSettings::default(),
+        };
+        let playing = summary(RoomStatus::Playing);
+        lobby.publish(playing.clone());
+        let (rooms, mut updates) = lobby.subscribe();
+        assert_eq!(vec![playing.clone()], rooms);
+
+        // unchanged rooms are not sent again
+        lobby.publish(playing.clone());
+        let waiting = summary(RoomStatus::Waiting);
+        lobby.publish(waiting.clone());
+        lobby.remove(playing.id);
+        assert_eq!(
+            LobbyUpdate::Room(waiting.clone()),
+            updates.try_recv().unwrap()
+        );
+        assert_eq!(
+            LobbyUpdate::Removed(playing.id),
+            updates.try_recv().unwrap()
+        );
+        assert!(updates.try_recv().is_err());
+
+        lobby.publish(playing.clone());
+        // open tables first
+        assert_eq!(vec![waiting, playing], lobby.rooms());
+    }
+
+    #[tokio::test]
+    async fn test_outbox_replay() {
+        let outbox = Outbox::new();
+        let user_id = Uuid::new_v4();
+        let mut receiver = outbox.register(user_id);
+        // stands for the room task
+        let mut room_receiver = outbox.room_sender.new_receiver();
+        tokio::spawn(async move { while room_receiver.recv().await.is_ok() {} });
+
+        for _ in 0..3 {
+            outbox
+                .send(RoomMessage::public(RoomMessageType::RoomClosed))
+                .await
+                .unwrap();
+        }
+        assert!(outbox.replay(user_id, 1));
+        assert_eq!(Some(2), receiver.try_recv().unwrap().seq);
+        assert_eq!(Some(3), receiver.try_recv().unwrap().seq);
+        assert!(receiver.try_recv().is_err());
+
+        // the room was restarted since
+        assert!(!outbox.replay(user_id, 4));
+
+        for _ in 0..EVENT_LOG_SIZE {
+            outbox
+                .send(RoomMessage::public(RoomMessageType::RoomClosed))
+                .await
+                .unwrap();
+        }
+        assert!(!outbox.replay(user_id, 1));
+    }
+
+    #[test]
+    fn test_presence_not_deserializable() {
+        assert!(serde_json::from_str::<RoomMessage>(r#"{"msgType": "disconnected"}"#).is_err());
+    }
+
     #[test]
     fn test_serialize_msg() {
         println!(
@@ -874,7 +2700,8 @@ mod test {
             serde_json::to_string_pretty(&RoomMessage {
                 from_user_id: Some(Uuid::new_v4()),
                 to_user_id: Some(Uuid::new_v4()),
-                msg_type: crate::room::RoomMessageType::Joined(Uuid::new_v4())
+                seq: None,
+                msg_type: crate::room::RoomMessageType::Joined(User::default())
             })
             .unwrap()
         );
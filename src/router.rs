@@ -1,25 +1,49 @@
-use crate::data::{Room, Rooms, User};
+use crate::data::{Lobby, Room, RoomSettings, Rooms, User, UserId};
 use crate::{
-    constants::{COOKIE as COOKIE_NAME, TIMEOUT_SECS, USER_ID},
-    db::upsert_user,
-    templ::{get_template, INDEX_PAGE, ROOM_PAGE},
-    utils::service_error,
+    constants::{
+        COOKIE as COOKIE_NAME, LEADERBOARD_SIZE, MAX_DELAY_SECS, MAX_HANDS, MAX_ROOMS,
+        MAX_SCORE_THRESHOLD, MAX_TIMEOUT_SECS, PASSWORD_MIN_LEN, RATING_HISTORY_SIZE,
+        SESSION_EXPIRY_SECS, USER_ID,
+    },
+    db::{
+        find_credentials_by_name, find_game_events, find_leaderboard, register_user, upsert_user,
+    },
+    replay::replay_steps,
+    room::is_seated,
+    session::SqliteSessionStore,
+    stats::find_user_profile,
+    templ::{
+        get_template, ERROR_PAGE, INDEX_PAGE, LEADERBOARD_PAGE, LOGIN_PAGE, PROFILE_PAGE,
+        REPLAY_PAGE, ROOM_PAGE, SIGNUP_PAGE, USER_PAGE,
+    },
+    user::{guest_name, hash_password, validate_name, validate_password, verify_password},
+    utils::{remove_cookie, service_error, FormOrJson},
     websocket::ws_handler,
 };
-use async_session::{MemoryStore, Session, SessionStore};
+use async_session::{Session, SessionStore};
 use axum::{
     extract::{FromRef, Path, State},
-    http::{header::SET_COOKIE, Request, StatusCode},
+    http::{header::SET_COOKIE, HeaderValue, Request, StatusCode},
     middleware::Next,
-    response::{ErrorResponse, Html, IntoResponse, Redirect},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        ErrorResponse, Html, IntoResponse, Redirect, Response,
+    },
     routing::{get, post},
-    Router,
+    Form, Json, Router,
 };
 use axum_extra::extract::CookieJar;
 use chrono::Local;
+use futures::{Stream, StreamExt};
 use minijinja::context;
+use serde_derive::Deserialize;
 use sqlx::{Pool, Sqlite};
-use std::{borrow::Cow, error::Error};
+use std::{
+    borrow::Cow,
+    error::Error,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
 use time::{macros::format_description, UtcOffset};
 use tower::ServiceBuilder;
 use tower_http::{
@@ -35,8 +59,9 @@ pub type WsEndpoint = Cow<'static, str>;
 #[derive(Clone)]
 pub struct AppState {
     pub rooms: Rooms,
+    pub lobby: Lobby,
     pub db_pool: Pool<Sqlite>,
-    pub store: MemoryStore,
+    pub store: SqliteSessionStore,
     pub ws_endpoint: WsEndpoint,
 }
 impl FromRef<AppState> for Rooms {
@@ -44,6 +69,11 @@ impl FromRef<AppState> for Rooms {
         app_state.rooms.clone()
     }
 }
+impl FromRef<AppState> for Lobby {
+    fn from_ref(app_state: &AppState) -> Lobby {
+        app_state.lobby.clone()
+    }
+}
 impl FromRef<AppState> for Pool<Sqlite> {
     fn from_ref(app_state: &AppState) -> Pool<Sqlite> {
         app_state.db_pool.clone()
@@ -54,8 +84,8 @@ impl FromRef<AppState> for WsEndpoint {
         app_state.ws_endpoint.clone()
     }
 }
-impl FromRef<AppState> for MemoryStore {
-    fn from_ref(app_state: &AppState) -> MemoryStore {
+impl FromRef<AppState> for SqliteSessionStore {
+    fn from_ref(app_state: &AppState) -> SqliteSessionStore {
         app_state.store.clone()
     }
 }
@@ -63,19 +93,31 @@ pub fn get_router(
     ws_endpoint: Cow<'static, str>,
     db_pool: Pool<Sqlite>,
     rooms: Rooms,
-    store: MemoryStore,
+    lobby: Lobby,
+    store: SqliteSessionStore,
 ) -> Router {
     let serve_dir = ServeDir::new("assets");
     let state = AppState {
         rooms,
+        lobby,
         db_pool,
         store,
         ws_endpoint,
     };
     Router::new()
         .route("/create-room", post(create_room))
+        .route("/lobby/events", get(lobby_events))
         .route("/room/:id", get(get_room))
         .route("/ws/:id", get(ws_handler))
+        .route("/game/:id/replay", get(replay_page))
+        .route("/game/:id/replay/:step", get(replay_step))
+        .route("/login", get(login_page).post(login))
+        .route("/signup", get(signup_page).post(signup))
+        .route("/logout", post(logout))
+        .route("/profile", get(profile_page).post(update_profile))
+        .route("/user/:id", get(user_page))
+        .route("/user/:id/stats", get(user_stats))
+        .route("/leaderboard", get(leaderboard_page))
         .route("/", get(index_page))
         .nest_service("/assets", serve_dir)
         .route(
@@ -117,25 +159,84 @@ pub fn setup_tracing() -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
-async fn index_page(State(rooms): State<Rooms>) -> axum::response::Result<impl IntoResponse> {
+async fn index_page(
+    State(lobby): State<Lobby>,
+    user: Option<User>,
+) -> axum::response::Result<impl IntoResponse> {
     let templ = get_template(
         INDEX_PAGE,
-        context! {rooms => rooms.iter().map(|e|*e.key()).collect::<Vec<_>>()},
+        context! {
+            rooms => lobby.rooms(),
+            user => user,
+            settings => RoomSettings::default(),
+            max_hands => MAX_HANDS,
+            max_timeout_secs => MAX_TIMEOUT_SECS,
+            max_delay_secs => MAX_DELAY_SECS,
+            max_score_threshold => MAX_SCORE_THRESHOLD,
+        },
     )
     .map_err(service_error)?;
     Ok(Html::from(templ))
 }
 
+// the rooms of the lobby, then every change to them
+async fn lobby_events(
+    State(lobby): State<Lobby>,
+) -> Sse<impl Stream<Item = Result<Event, serde_json::Error>>> {
+    let updates = lobby
+        .feed()
+        .map(|update| Event::default().json_data(update));
+    Sse::new(updates).keep_alive(KeepAlive::default())
+}
+
+// rooms being created, they count towards MAX_ROOMS until they are in the map
+static CREATING_ROOMS: AtomicUsize = AtomicUsize::new(0);
+
+// a room that is being created, released once it is in the map or creation failed
+struct RoomSlot;
+
+impl RoomSlot {
+    fn reserve(rooms: &Rooms) -> Option<RoomSlot> {
+        let creating = CREATING_ROOMS.fetch_add(1, Ordering::SeqCst);
+        let slot = RoomSlot;
+        (rooms.len() + creating < MAX_ROOMS).then_some(slot)
+    }
+}
+
+impl Drop for RoomSlot {
+    fn drop(&mut self) {
+        CREATING_ROOMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 async fn create_room(
     State(rooms): State<Rooms>,
+    State(lobby): State<Lobby>,
     State(pool): State<Pool<Sqlite>>,
+    user: User,
+    FormOrJson(settings): FormOrJson<RoomSettings>,
 ) -> axum::response::Result<impl IntoResponse> {
-    let (id, room) = Room::new(pool).await;
+    settings
+        .validate()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let Some(_slot) = RoomSlot::reserve(&rooms) else {
+        tracing::warn!("cannot create a room, {MAX_ROOMS} rooms are open");
+        let templ = get_template(
+            ERROR_PAGE,
+            context!(
+                title => "No table available",
+                message => "All the tables are taken. Join an open room or try again in a few minutes."
+            ),
+        )
+        .map_err(service_error)?;
+        return Ok((StatusCode::SERVICE_UNAVAILABLE, Html::from(templ)).into_response());
+    };
+    let (id, room) = Room::new(pool, rooms.clone(), lobby, settings, user.id).await;
     let response = Redirect::to(&format!("/room/{}", id));
 
     rooms.insert(id, room);
 
-    Ok(response)
+    Ok(response.into_response())
 }
 
 async fn get_room(
@@ -153,7 +254,7 @@ async fn get_room(
                 room => *room,
                 ws_endpoint => ws_endpoint,
                 user => user,
-                timeout => TIMEOUT_SECS
+                timeout => room.settings.timeout_secs
             ),
         )
         .map_err(service_error)?;
@@ -163,34 +264,279 @@ async fn get_room(
     Err(ErrorResponse::from(StatusCode::NOT_FOUND))
 }
 
+async fn replay_page(
+    Path(id): Path<Uuid>,
+    State(pool): State<Pool<Sqlite>>,
+    user: User,
+) -> axum::response::Result<impl IntoResponse> {
+    let events = find_game_events(id, &pool).await.map_err(service_error)?;
+    let Some(steps) = replay_steps(&events) else {
+        return Err(ErrorResponse::from(StatusCode::NOT_FOUND));
+    };
+    let templ = get_template(
+        REPLAY_PAGE,
+        context!(game_id => id, user => user, steps => steps.len()),
+    )
+    .map_err(service_error)?;
+    Ok(Html::from(templ))
+}
+
+async fn replay_step(
+    Path((id, step)): Path<(Uuid, usize)>,
+    State(pool): State<Pool<Sqlite>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let events = find_game_events(id, &pool).await.map_err(service_error)?;
+    let step = replay_steps(&events).and_then(|mut steps| {
+        if step < steps.len() {
+            Some(steps.swap_remove(step))
+        } else {
+            None
+        }
+    });
+    let Some(step) = step else {
+        return Err(ErrorResponse::from(StatusCode::NOT_FOUND));
+    };
+    Ok(Json(step))
+}
+
+#[derive(Deserialize)]
+struct LoginForm {
+    name: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+struct SignupForm {
+    name: String,
+    password: String,
+    confirm_password: String,
+}
+
+async fn login_page() -> axum::response::Result<impl IntoResponse> {
+    let templ = get_template(LOGIN_PAGE, context! {}).map_err(service_error)?;
+    Ok(Html::from(templ))
+}
+
+async fn login(
+    State(store): State<SqliteSessionStore>,
+    State(pool): State<Pool<Sqlite>>,
+    cookies: CookieJar,
+    Form(form): Form<LoginForm>,
+) -> axum::response::Result<Response> {
+    let credentials = find_credentials_by_name(form.name.trim(), &pool)
+        .await
+        .map_err(service_error)?;
+    let Some((user_id, _)) =
+        credentials.filter(|(_, password_hash)| verify_password(&form.password, password_hash))
+    else {
+        let templ = get_template(
+            LOGIN_PAGE,
+            context! {name => form.name, error => "invalid name or password"},
+        )
+        .map_err(service_error)?;
+        return Ok((StatusCode::UNAUTHORIZED, Html::from(templ)).into_response());
+    };
+    // the guest session is replaced by the one of the registered user
+    destroy_current_session(&store, &cookies).await;
+    let cookie = new_session_cookie(&store, user_id).await?;
+    let mut response = Redirect::to("/").into_response();
+    response.headers_mut().insert(SET_COOKIE, cookie);
+    Ok(response)
+}
+
+async fn logout(State(store): State<SqliteSessionStore>, cookies: CookieJar) -> Response {
+    destroy_current_session(&store, &cookies).await;
+    remove_cookie(Redirect::to("/").into_response())
+}
+
+async fn signup_page(user: User) -> axum::response::Result<Response> {
+    if !user.is_guest {
+        return Ok(Redirect::to("/").into_response());
+    }
+    let templ = get_template(SIGNUP_PAGE, context! {password_min_len => PASSWORD_MIN_LEN})
+        .map_err(service_error)?;
+    Ok(Html::from(templ).into_response())
+}
+
+// the current guest is upgraded to a registered user, so it keeps its id and history
+async fn signup(
+    State(pool): State<Pool<Sqlite>>,
+    user: User,
+    Form(form): Form<SignupForm>,
+) -> axum::response::Result<Response> {
+    if !user.is_guest {
+        return Ok(Redirect::to("/").into_response());
+    }
+    let form_error = |error: &str| -> Response {
+        match get_template(
+            SIGNUP_PAGE,
+            context! {name => form.name, error => error, password_min_len => PASSWORD_MIN_LEN},
+        ) {
+            Ok(templ) => (StatusCode::BAD_REQUEST, Html::from(templ)).into_response(),
+            Err(e) => service_error(e).into_response(),
+        }
+    };
+    let name = match validate_name(&form.name) {
+        Ok(name) => name,
+        Err(e) => return Ok(form_error(e)),
+    };
+    if let Err(e) = validate_password(&form.password) {
+        return Ok(form_error(e));
+    }
+    if form.password != form.confirm_password {
+        return Ok(form_error("passwords do not match"));
+    }
+    if find_credentials_by_name(&name, &pool)
+        .await
+        .map_err(service_error)?
+        .is_some()
+    {
+        return Ok(form_error("name already taken"));
+    }
+    let password_hash = hash_password(&form.password).map_err(service_error)?;
+    let user = User { name, ..user }.is_guest(false);
+    register_user(user, &password_hash, &pool)
+        .await
+        .map_err(|e| service_error(format!("couldn't register user {user:?} => {e}")))?;
+    Ok(Redirect::to("/").into_response())
+}
+
+#[derive(Deserialize)]
+struct ProfileForm {
+    name: String,
+}
+
+async fn profile_page(user: User) -> axum::response::Result<impl IntoResponse> {
+    let templ = get_template(PROFILE_PAGE, context! {user => user}).map_err(service_error)?;
+    Ok(Html::from(templ))
+}
+
+async fn user_page(
+    Path(id): Path<UserId>,
+    State(pool): State<Pool<Sqlite>>,
+    user: User,
+) -> axum::response::Result<impl IntoResponse> {
+    let Ok(profile) = find_user_profile(id, &pool).await else {
+        return Err(ErrorResponse::from(StatusCode::NOT_FOUND));
+    };
+    let templ = get_template(USER_PAGE, context!(profile => profile, user => user))
+        .map_err(service_error)?;
+    Ok(Html::from(templ))
+}
+
+async fn user_stats(
+    Path(id): Path<UserId>,
+    State(pool): State<Pool<Sqlite>>,
+) -> axum::response::Result<impl IntoResponse> {
+    let Ok(profile) = find_user_profile(id, &pool).await else {
+        return Err(ErrorResponse::from(StatusCode::NOT_FOUND));
+    };
+    Ok(Json(profile))
+}
+
+async fn leaderboard_page(
+    State(pool): State<Pool<Sqlite>>,
+    user: User,
+) -> axum::response::Result<impl IntoResponse> {
+    let leaderboard = find_leaderboard(LEADERBOARD_SIZE, RATING_HISTORY_SIZE, &pool)
+        .await
+        .map_err(service_error)?;
+    let templ = get_template(
+        LEADERBOARD_PAGE,
+        context!(leaderboard => leaderboard, user => user),
+    )
+    .map_err(service_error)?;
+    Ok(Html::from(templ))
+}
+
+async fn update_profile(
+    State(pool): State<Pool<Sqlite>>,
+    State(rooms): State<Rooms>,
+    user: User,
+    Form(form): Form<ProfileForm>,
+) -> axum::response::Result<Response> {
+    let form_error = |error: &str| -> Response {
+        match get_template(PROFILE_PAGE, context! {user => user, error => error}) {
+            Ok(templ) => (StatusCode::BAD_REQUEST, Html::from(templ)).into_response(),
+            Err(e) => service_error(e).into_response(),
+        }
+    };
+    let name = match validate_name(&form.name) {
+        Ok(name) => name,
+        Err(e) => return Ok(form_error(e)),
+    };
+    if find_credentials_by_name(&name, &pool)
+        .await
+        .map_err(service_error)?
+        .is_some_and(|(id, _)| id != user.id)
+    {
+        return Ok(form_error("name already taken"));
+    }
+    // the tables check names of their own, rename from the table instead
+    if is_seated(&rooms, user.id).await {
+        return Ok(form_error("leave your table to change your name"));
+    }
+    let user = User { name, ..user };
+    upsert_user(user, &pool)
+        .await
+        .map_err(|e| service_error(format!("couldn't save user {user:?} => {e}")))?;
+    Ok(Redirect::to("/profile").into_response())
+}
+
+// store a new session for the user and build the corresponding cookie
+async fn new_session_cookie(
+    store: &SqliteSessionStore,
+    user_id: UserId,
+) -> axum::response::Result<HeaderValue> {
+    let mut session = Session::new();
+    session.expire_in(Duration::from_secs(SESSION_EXPIRY_SECS));
+    session.insert(USER_ID, user_id).map_err(service_error)?;
+    // Store session and get corresponding cookie
+    let cookie = store.store_session(session).await.map_err(service_error)?;
+    let cookie = cookie.ok_or_else(|| service_error("failed  to store session"))?;
+    // Build the cookie
+    let cookie = format!(
+        "{}={}; SameSite=Lax; Path=/; Max-Age={}",
+        COOKIE_NAME, cookie, SESSION_EXPIRY_SECS
+    );
+    Ok(cookie.parse().map_err(service_error)?)
+}
+
+async fn destroy_current_session(store: &SqliteSessionStore, cookies: &CookieJar) {
+    let Some(cookie) = cookies.get(COOKIE_NAME) else {
+        return;
+    };
+    match store.load_session(cookie.value().to_string()).await {
+        Ok(Some(session)) => {
+            if let Err(e) = store.destroy_session(session).await {
+                tracing::error!("could not destroy session: {e}");
+            }
+        }
+        Ok(None) => tracing::debug!("session already gone"),
+        Err(e) => tracing::error!("could not load session: {e}"),
+    }
+}
+
 pub async fn build_guest_session_if_none<B>(
-    State(store): State<MemoryStore>,
+    State(store): State<SqliteSessionStore>,
     State(pool): State<Pool<Sqlite>>,
     request: Request<B>,
     next: Next<B>,
 ) -> axum::response::Result<impl IntoResponse> {
     let cookies = CookieJar::from_headers(request.headers());
     let mut response = next.run(request).await;
-    if cookies.get(COOKIE_NAME).is_none() {
+    // the handler may have set the session itself, e.g on login
+    if cookies.get(COOKIE_NAME).is_none() && !response.headers().contains_key(SET_COOKIE) {
         tracing::debug!("session doesn't exist, create one");
         let id = Uuid::new_v4();
-        let mut session = Session::new();
-        session.insert(USER_ID, id).map_err(service_error)?;
-        // Store session and get corresponding cookie
-        let cookie = store.store_session(session).await.map_err(service_error)?;
-        tracing::debug!("{cookie:?}");
-        let cookie = cookie.ok_or_else(|| service_error("failed  to store session"))?;
-        // Build the cookie
-        let cookie = format!("{}={}; SameSite=Lax; Path=/", COOKIE_NAME, cookie);
+        let cookie = new_session_cookie(&store, id).await?;
         // Set cookie
-        response
-            .headers_mut()
-            .insert(SET_COOKIE, cookie.parse().map_err(service_error)?);
+        response.headers_mut().insert(SET_COOKIE, cookie);
         let user = User::default()
             .with_id(id)
             .human(true)
             .is_guest(true)
-            .name(format!("Guest{id}"));
+            .name(guest_name(id));
         upsert_user(user, &pool)
             .await
             .map_err(|e| service_error(format!("couldn't save user {user:?} => {e}")))?;
@@ -0,0 +1,172 @@
+use std::{error::Error, fs};
+
+use schemars::{schema_for, JsonSchema};
+use serde_json::Value;
+use uuid::Uuid;
+
+use crate::{
+    constants::{SCHEMA_DIR, SCHEMA_PATH, TYPESCRIPT_PATH},
+    data::RoomMessage,
+};
+
+// schemas of the lib_hearts types used in the messages,
+// they must be kept in sync with how lib_hearts serializes them
+#[derive(JsonSchema)]
+#[schemars(rename = "PlayerState")]
+pub struct PlayerStateDef {
+    pub player_id: Uuid,
+    pub score: usize,
+}
+
+#[derive(JsonSchema)]
+#[schemars(rename = "GameError", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum GameErrorDef {
+    UnknownCard,
+    ForbiddenMove,
+    PlayerDoesntHaveCard,
+    HeartNeverPlayedBefore,
+    MustUseStartCard,
+    MustPlaySameKind,
+    CannotStartWithQueenOrHeart,
+    StateError,
+}
+
+#[derive(JsonSchema)]
+#[schemars(rename = "TypeCard", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum TypeCardDef {
+    Heart,
+    Spade,
+    Diamond,
+    Club,
+}
+
+pub fn json_schema() -> String {
+    let schema = schema_for!(RoomMessage);
+    serde_json::to_string_pretty(&schema).expect("could not serialize the schema") + "\n"
+}
+
+// typescript definitions derived from the json schema
+pub fn typescript() -> String {
+    let schema = serde_json::to_value(schema_for!(RoomMessage)).expect("invalid schema");
+    let mut ts = String::from("// generated by `cargo run -- schema`, do not edit\n");
+    if let Some(definitions) = schema["definitions"].as_object() {
+        for (name, definition) in definitions {
+            ts.push_str(&format!(
+                "\nexport type {name} = {};\n",
+                ts_type(definition, 0)
+            ));
+        }
+    }
+    ts.push_str(&format!(
+        "\nexport type RoomMessage = {};\n",
+        ts_type(&schema, 0)
+    ));
+    ts
+}
+
+fn ts_type(schema: &Value, indent: usize) -> String {
+    if let Some(reference) = schema["$ref"].as_str() {
+        return reference
+            .rsplit('/')
+            .next()
+            .unwrap_or(reference)
+            .to_string();
+    }
+    for key in ["allOf", "oneOf", "anyOf"] {
+        if let Some(schemas) = schema[key].as_array() {
+            let separator = if key == "allOf" { " & " } else { " | " };
+            return schemas
+                .iter()
+                .map(|s| ts_type(s, indent))
+                .collect::<Vec<_>>()
+                .join(separator);
+        }
+    }
+    if let Some(values) = schema["enum"].as_array() {
+        return values
+            .iter()
+            .map(|v| v.to_string())
+            .collect::<Vec<_>>()
+            .join(" | ");
+    }
+    match &schema["type"] {
+        // e.g. ["string", "null"] for an option
+        Value::Array(types) => types
+            .iter()
+            .map(|t| {
+                let mut schema = schema.clone();
+                schema["type"] = t.clone();
+                ts_type(&schema, indent)
+            })
+            .collect::<Vec<_>>()
+            .join(" | "),
+        Value::String(t) => match t.as_str() {
+            "null" => "null".to_string(),
+            "string" => "string".to_string(),
+            "integer" | "number" => "number".to_string(),
+            "boolean" => "boolean".to_string(),
+            "array" => {
+                let item = ts_type(&schema["items"], indent);
+                if item.contains(" | ") {
+                    format!("({item})[]")
+                } else {
+                    format!("{item}[]")
+                }
+            }
+            "object" => ts_object(schema, indent),
+            _ => "unknown".to_string(),
+        },
+        _ => "unknown".to_string(),
+    }
+}
+
+fn ts_object(schema: &Value, indent: usize) -> String {
+    let Some(properties) = schema["properties"].as_object() else {
+        return "Record<string, unknown>".to_string();
+    };
+    let required: Vec<&str> = schema["required"]
+        .as_array()
+        .map(|r| r.iter().filter_map(|v| v.as_str()).collect())
+        .unwrap_or_default();
+    let pad = "  ".repeat(indent + 1);
+    let mut ts = String::from("{\n");
+    for (name, property) in properties {
+        let optional = if required.contains(&name.as_str()) {
+            ""
+        } else {
+            "?"
+        };
+        ts.push_str(&format!(
+            "{pad}{name}{optional}: {};\n",
+            ts_type(property, indent + 1)
+        ));
+    }
+    ts.push_str(&"  ".repeat(indent));
+    ts.push('}');
+    ts
+}
+
+pub fn write_files() -> Result<(), Box<dyn Error>> {
+    fs::create_dir_all(SCHEMA_DIR)?;
+    fs::write(SCHEMA_PATH, json_schema())?;
+    fs::write(TYPESCRIPT_PATH, typescript())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{json_schema, typescript};
+
+    // run `cargo run -- schema` when the messages change
+    #[test]
+    fn test_schema_drift() {
+        assert_eq!(
+            include_str!("../assets/schema/room-message.schema.json"),
+            json_schema()
+        );
+        assert_eq!(
+            include_str!("../assets/schema/room-message.d.ts"),
+            typescript()
+        );
+    }
+}
@@ -0,0 +1,136 @@
+use std::time::Duration;
+
+use async_session::{async_trait, Result, Session, SessionStore};
+use chrono::Utc;
+use sqlx::{Pool, Sqlite};
+use tokio::task::JoinHandle;
+
+// session store backed by the sqlite database,
+// so guests keep their identity across restarts.
+#[derive(Debug, Clone)]
+pub struct SqliteSessionStore {
+    pool: Pool<Sqlite>,
+}
+
+#[async_trait]
+impl SessionStore for SqliteSessionStore {
+    async fn load_session(&self, cookie_value: String) -> Result<Option<Session>> {
+        let id = Session::id_from_cookie_value(&cookie_value)?;
+        let now = Utc::now().timestamp();
+        let row = sqlx::query!(
+            "select session from sessions where id = ? and (expires is null or expires > ?)",
+            id,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let session: Session = serde_json::from_str(&row.session)?;
+        Ok(session.validate())
+    }
+
+    async fn store_session(&self, session: Session) -> Result<Option<String>> {
+        let id = session.id().to_string();
+        let data = serde_json::to_string(&session)?;
+        let expires = session.expiry().map(|expiry| expiry.timestamp());
+        let _ = sqlx::query!(
+            r#"
+            INSERT into sessions (id, session, expires)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT DO UPDATE SET session=?2, expires=?3;
+        "#,
+            id,
+            data,
+            expires
+        )
+        .execute(&self.pool)
+        .await?;
+
+        session.reset_data_changed();
+        Ok(session.into_cookie_value())
+    }
+
+    async fn destroy_session(&self, session: Session) -> Result {
+        let id = session.id();
+        let _ = sqlx::query!("delete from sessions where id = ?", id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn clear_store(&self) -> Result {
+        let _ = sqlx::query!("delete from sessions")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+impl SqliteSessionStore {
+    pub fn new(pool: Pool<Sqlite>) -> Self {
+        Self { pool }
+    }
+
+    // removes expired sessions
+    pub async fn cleanup(&self) -> Result<u64> {
+        let now = Utc::now().timestamp();
+        let res = sqlx::query!("delete from sessions where expires <= ?", now)
+            .execute(&self.pool)
+            .await?;
+        Ok(res.rows_affected())
+    }
+
+    pub fn spawn_cleanup_task(&self, period: Duration) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                match store.cleanup().await {
+                    Ok(count) => tracing::debug!("removed {count} expired session(s)"),
+                    Err(e) => tracing::error!("could not cleanup sessions: {e}"),
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use async_session::{Session, SessionStore};
+    use uuid::Uuid;
+
+    use super::SqliteSessionStore;
+    use crate::{constants::USER_ID, db::test_pool};
+
+    async fn store() -> SqliteSessionStore {
+        SqliteSessionStore::new(test_pool().await)
+    }
+
+    #[tokio::test]
+    async fn test_store_and_load_session() {
+        let store = store().await;
+        let user_id = Uuid::new_v4();
+        let mut session = Session::new();
+        session.insert(USER_ID, user_id).unwrap();
+        let cookie = store.store_session(session).await.unwrap().unwrap();
+
+        let session = store.load_session(cookie).await.unwrap().unwrap();
+        assert_eq!(Some(user_id), session.get::<Uuid>(USER_ID));
+    }
+
+    #[tokio::test]
+    async fn test_expired_session() {
+        let store = store().await;
+        let mut session = Session::new();
+        session.expire_in(Duration::ZERO);
+        let cookie = store.store_session(session).await.unwrap().unwrap();
+
+        assert!(store.load_session(cookie).await.unwrap().is_none());
+        assert_eq!(1, store.cleanup().await.unwrap());
+    }
+}
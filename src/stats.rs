@@ -0,0 +1,196 @@
+use std::error::Error;
+
+use lib_hearts::{get_card_by_idx, MAX_SCORE, PLAYER_NUMBER, QUEEN_OF_SPADE};
+use sqlx::{Pool, Sqlite};
+use uuid::Uuid;
+
+use crate::{
+    data::{GameEvent, GameEventRow, UserId, UserProfile, UserStats},
+    db::{add_user_stats, find_game_events, find_user_by_id, find_user_stats},
+};
+
+// what a finished game adds to the stats of its players, bots excluded.
+// none if the log doesn't hold a whole game
+pub fn game_stats(events: &[GameEventRow]) -> Option<Vec<(UserId, UserStats)>> {
+    let (first, rest) = events.split_first()?;
+    let GameEvent::Started { players, .. } = first.event else {
+        return None;
+    };
+    let pos = |player_id: UserId| players.iter().position(|p| p.id == player_id);
+    let mut stats = [UserStats {
+        games_played: 1,
+        ..Default::default()
+    }; PLAYER_NUMBER];
+    // points taken during the hand, before shooting the moon is applied
+    let mut hand_points = [0; PLAYER_NUMBER];
+    let mut ended = false;
+
+    for row in rest {
+        match &row.event {
+            GameEvent::Dealt { .. } => hand_points = [0; PLAYER_NUMBER],
+            GameEvent::TrickTaken(trick) => {
+                let winner = pos(trick.winner)?;
+                hand_points[winner] += trick.points;
+                if trick
+                    .cards
+                    .iter()
+                    .any(|c| get_card_by_idx(c.card.position_in_deck) == &QUEEN_OF_SPADE)
+                {
+                    stats[winner].queens_taken += 1;
+                }
+            }
+            GameEvent::HandScored { .. } => {
+                if let Some(moon) = hand_points.iter().position(|p| *p == MAX_SCORE) {
+                    stats[moon].moons_shot += 1;
+                }
+            }
+            GameEvent::Ended {
+                player_scores,
+                winners,
+            } => {
+                for player_state in player_scores {
+                    let player = pos(player_state.player_id)?;
+                    stats[player].total_points = player_state.score as i64;
+                    stats[player].wins = winners.contains(&player_state.player_id) as i64;
+                }
+                ended = true;
+            }
+            _ => {}
+        }
+    }
+    if !ended {
+        return None;
+    }
+    Some(
+        players
+            .iter()
+            .zip(stats)
+            .filter(|(player, _)| !player.bot)
+            .map(|(player, stats)| (player.id, stats))
+            .collect(),
+    )
+}
+
+pub async fn record_game_stats(game_id: Uuid, pool: &Pool<Sqlite>) -> Result<(), Box<dyn Error>> {
+    let events = find_game_events(game_id, pool).await?;
+    let stats = game_stats(&events).ok_or("game is not over")?;
+    for (user_id, stats) in stats {
+        add_user_stats(user_id, stats, pool).await?;
+    }
+    Ok(())
+}
+
+pub async fn find_user_profile(
+    user_id: UserId,
+    pool: &Pool<Sqlite>,
+) -> Result<UserProfile, Box<dyn Error>> {
+    let user = find_user_by_id(user_id, pool).await?;
+    let stats = find_user_stats(user_id, pool).await?;
+    let average_points = if stats.games_played > 0 {
+        stats.total_points as f64 / stats.games_played as f64
+    } else {
+        0.
+    };
+    Ok(UserProfile {
+        user,
+        stats,
+        average_points,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use arraystring::ArrayString;
+    use lib_hearts::{
+        get_card_by_idx, PlayerState, TypeCard, DECK_SIZE, PLAYER_NUMBER, QUEEN_OF_SPADE,
+    };
+    use uuid::Uuid;
+
+    use super::game_stats;
+    use crate::{
+        data::{GameEvent, PlayedCard, PlayerCard, RoomSettings, Trick, UserStats},
+        db::{test_event_row, test_players},
+    };
+
+    #[test]
+    fn test_game_stats() {
+        let game_id = Uuid::new_v4();
+        let players = test_players([
+            (false, false),
+            (false, false),
+            (false, false),
+            (false, true),
+        ]);
+        let ids = players.map(|p| p.id);
+        let row = |event| test_event_row(game_id, None, event);
+        let queen = PlayedCard {
+            player_id: ids[0],
+            card: PlayerCard {
+                type_card: TypeCard::Spade,
+                emoji: ArrayString::from_utf8("🂭").unwrap(),
+                position_in_deck: (0..DECK_SIZE)
+                    .find(|idx| get_card_by_idx(*idx) == &QUEEN_OF_SPADE)
+                    .unwrap(),
+            },
+        };
+        let player_scores: [PlayerState; PLAYER_NUMBER] = std::array::from_fn(|i| PlayerState {
+            player_id: ids[i],
+            score: if i == 0 { 0 } else { 26 },
+        });
+        let mut events = vec![row(GameEvent::Started {
+            room_id: Uuid::new_v4(),
+            players,
+            settings: RoomSettings::default(),
+        })];
+        // the first player takes every point of the hand
+        events.push(row(GameEvent::TrickTaken(Trick {
+            cards: vec![queen],
+            leader: ids[0],
+            winner: ids[0],
+            points: 13,
+        })));
+        events.push(row(GameEvent::TrickTaken(Trick {
+            cards: vec![],
+            leader: ids[0],
+            winner: ids[0],
+            points: 13,
+        })));
+        events.push(row(GameEvent::HandScored {
+            hand: 1,
+            player_scores,
+        }));
+        assert!(game_stats(&events).is_none());
+
+        events.push(row(GameEvent::Ended {
+            player_scores,
+            winners: vec![ids[0]],
+        }));
+        let stats = game_stats(&events).unwrap();
+        // bots are not tracked
+        assert_eq!(PLAYER_NUMBER - 1, stats.len());
+        assert_eq!(
+            (
+                ids[0],
+                UserStats {
+                    games_played: 1,
+                    wins: 1,
+                    total_points: 0,
+                    moons_shot: 1,
+                    queens_taken: 1,
+                }
+            ),
+            stats[0]
+        );
+        assert_eq!(
+            (
+                ids[1],
+                UserStats {
+                    games_played: 1,
+                    total_points: 26,
+                    ..Default::default()
+                }
+            ),
+            stats[1]
+        );
+    }
+}
@@ -7,6 +7,14 @@ static ENGINE: OnceLock<Environment<'static>> = OnceLock::new();
 
 pub static INDEX_PAGE: &str = "index.html";
 pub static ROOM_PAGE: &str = "room.html";
+pub static LOGIN_PAGE: &str = "login.html";
+pub static SIGNUP_PAGE: &str = "signup.html";
+pub static PROFILE_PAGE: &str = "profile.html";
+pub static REPLAY_PAGE: &str = "replay.html";
+pub static USER_PAGE: &str = "user.html";
+pub static LEADERBOARD_PAGE: &str = "leaderboard.html";
+pub static ERROR_PAGE: &str = "error.html";
+pub static TABLE_MACROS: &str = "table.html";
 pub static BASE_LAYOUT: &str = "base.html";
 
 pub fn get_template<S: Serialize>(tpl: &str, ctx: S) -> Result<String, Box<dyn Error>> {
@@ -26,5 +34,13 @@ fn init_engine() -> Result<Environment<'static>, Box<dyn Error>> {
     env.add_template(BASE_LAYOUT, include_str!("templates/base.html"))?;
     env.add_template(INDEX_PAGE, include_str!("templates/index.html"))?;
     env.add_template(ROOM_PAGE, include_str!("templates/room.html"))?;
+    env.add_template(LOGIN_PAGE, include_str!("templates/login.html"))?;
+    env.add_template(SIGNUP_PAGE, include_str!("templates/signup.html"))?;
+    env.add_template(PROFILE_PAGE, include_str!("templates/profile.html"))?;
+    env.add_template(REPLAY_PAGE, include_str!("templates/replay.html"))?;
+    env.add_template(USER_PAGE, include_str!("templates/user.html"))?;
+    env.add_template(LEADERBOARD_PAGE, include_str!("templates/leaderboard.html"))?;
+    env.add_template(ERROR_PAGE, include_str!("templates/error.html"))?;
+    env.add_template(TABLE_MACROS, include_str!("templates/table.html"))?;
     Ok(env)
 }
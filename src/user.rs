@@ -1,3 +1,7 @@
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use arraystring::ArrayString;
 use async_session::{async_trait, SessionStore};
 use axum::{
@@ -11,7 +15,7 @@ use rand::RngCore;
 use uuid::Uuid;
 
 use crate::{
-    constants::{COOKIE, USER_ID},
+    constants::{COOKIE, PASSWORD_MIN_LEN, USER_ID},
     data::{User, UserId},
     db::find_user_by_id,
     router::AppState,
@@ -52,6 +56,52 @@ impl User {
     }
 }
 
+// short enough to fit in the name, e.g Guest1a2b3c4
+pub fn guest_name(id: UserId) -> String {
+    let id = id.simple().to_string();
+    format!("Guest{}", &id[..7])
+}
+
+pub fn validate_name(name: &str) -> Result<ArrayString<typenum::U12>, &'static str> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err("name cannot be empty");
+    }
+    if !name
+        .chars()
+        .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    {
+        return Err("name can only contain letters, digits, '_' and '-'");
+    }
+    ArrayString::try_from_str(name).map_err(|_| "name is too long")
+}
+
+pub fn validate_password(password: &str) -> Result<(), &'static str> {
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        return Err("password is too short");
+    }
+    Ok(())
+}
+
+// salted argon2 hash, in the PHC string format
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default().hash_password(password.as_bytes(), &salt)?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    match PasswordHash::new(password_hash) {
+        Ok(hash) => Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok(),
+        Err(e) => {
+            tracing::error!("invalid password hash: {e}");
+            false
+        }
+    }
+}
+
 #[async_trait]
 impl<B> FromRequestParts<B> for User
 where
@@ -102,3 +152,33 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use uuid::Uuid;
+
+    use super::{guest_name, hash_password, validate_name, verify_password};
+
+    #[test]
+    fn test_validate_name() {
+        assert_eq!("nordine", validate_name(" nordine ").unwrap().as_str());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("no spaces").is_err());
+        assert!(validate_name("waytoolongname").is_err());
+    }
+
+    #[test]
+    fn test_guest_name() {
+        let name = guest_name(Uuid::new_v4());
+        assert_eq!(12, name.len());
+        assert_eq!(name, validate_name(&name).unwrap().as_str());
+    }
+
+    #[test]
+    fn test_hash_password() {
+        let hash = hash_password("hunter22").unwrap();
+        assert_ne!("hunter22", hash);
+        assert!(verify_password("hunter22", &hash));
+        assert!(!verify_password("hunter23", &hash));
+    }
+}
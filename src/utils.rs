@@ -2,33 +2,78 @@ use std::{error::Error, fmt::Display, mem::MaybeUninit};
 
 use crate::constants;
 use axum::{
-    http::{header::SET_COOKIE, StatusCode},
+    async_trait,
+    body::HttpBody,
+    extract::FromRequest,
+    http::{
+        header::{CONTENT_TYPE, SET_COOKIE},
+        Request, StatusCode,
+    },
     response::{IntoResponse, Redirect, Response},
+    BoxError, Form, Json,
 };
 use chrono::Local;
 use constants::COOKIE as COOKIE_NAME;
 use futures_util::Future;
+use serde::de::DeserializeOwned;
 #[derive(Debug)]
 pub struct HomePageRedirect;
 
 impl IntoResponse for HomePageRedirect {
     fn into_response(self) -> Response {
         tracing::debug!("in case of an error, remove cookie");
-        let mut resp = Redirect::temporary("/").into_response();
-        let now = Local::now().to_rfc2822();
-        let cookie = format!("{}=; SameSite=Lax; Path=/; expires={}", COOKIE_NAME, now);
-        match cookie.parse() {
-            Ok(cookie) => {
-                resp.headers_mut().insert(SET_COOKIE, cookie);
-                resp
-            }
-            Err(e) => {
-                tracing::error!("could not parse cookie. {e}");
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
-            }
+        remove_cookie(Redirect::temporary("/").into_response())
+    }
+}
+
+pub fn remove_cookie(mut resp: Response) -> Response {
+    let now = Local::now().to_rfc2822();
+    let cookie = format!("{}=; SameSite=Lax; Path=/; expires={}", COOKIE_NAME, now);
+    match cookie.parse() {
+        Ok(cookie) => {
+            resp.headers_mut().insert(SET_COOKIE, cookie);
+            resp
+        }
+        Err(e) => {
+            tracing::error!("could not parse cookie. {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+// accepts either a json body or a form, depending on the content type
+pub struct FormOrJson<T>(pub T);
+
+#[async_trait]
+impl<S, B, T> FromRequest<S, B> for FormOrJson<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let is_json = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .is_some_and(|content_type| content_type.starts_with("application/json"));
+        if is_json {
+            let Json(payload) = Json::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            Ok(FormOrJson(payload))
+        } else {
+            let Form(payload) = Form::<T>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            Ok(FormOrJson(payload))
         }
     }
 }
+
 pub fn service_error(e: impl Display) -> impl IntoResponse {
     tracing::error!("service error: {e}");
     StatusCode::INTERNAL_SERVER_ERROR
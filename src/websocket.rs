@@ -1,28 +1,72 @@
-use std::{borrow::Cow, net::SocketAddr, ops::ControlFlow};
+use std::{borrow::Cow, net::SocketAddr, ops::ControlFlow, time::Duration};
 
-use async_broadcast::Receiver;
-use async_session::MemoryStore;
+use async_broadcast::RecvError;
 use axum::{
     extract::{
-        ws::{CloseFrame, Message, WebSocket},
-        ConnectInfo, Path, State, WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        ConnectInfo, Path, Query, State, WebSocketUpgrade,
     },
     headers,
-    http::StatusCode,
+    http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, StatusCode},
     response::{ErrorResponse, IntoResponse},
     TypedHeader,
 };
 use futures_util::{stream::SplitSink, SinkExt, StreamExt};
+use serde_derive::Deserialize;
+use tokio::{sync::mpsc, time::timeout};
 use uuid::Uuid;
 
-use crate::data::{RoomMessage, Rooms, User, UserId};
+use crate::{
+    constants::{CLOSE_TIMEOUT_SECS, MAX_PROTOCOL_ERRORS},
+    data::{
+        wire::{self, Encoding, Frame, Protocol},
+        Outbox, ProtocolErrorCode, RoomMessage, RoomMessageType, Rooms, User, UserId,
+    },
+    session::SqliteSessionStore,
+};
+
+#[derive(Deserialize)]
+pub struct WsParams {
+    // for clients that cannot set the Sec-WebSocket-Protocol header
+    protocol: Option<String>,
+}
+
+// the query parameter wins over the sub protocols offered by the client
+fn negotiate_protocol(headers: &HeaderMap, protocol: Option<&str>) -> Result<Protocol, String> {
+    let unsupported = |offered: &str| {
+        let supported: Vec<String> = Protocol::supported()
+            .into_iter()
+            .map(|p| p.name())
+            .collect();
+        format!(
+            "unsupported protocol `{offered}`, supported protocols: {}",
+            supported.join(", ")
+        )
+    };
+    if let Some(protocol) = protocol {
+        return Protocol::from_name(protocol).ok_or_else(|| unsupported(protocol));
+    }
+    let Some(offered) = headers
+        .get(SEC_WEBSOCKET_PROTOCOL)
+        .and_then(|h| h.to_str().ok())
+    else {
+        return Ok(Protocol::DEFAULT);
+    };
+    offered
+        .split(',')
+        .find_map(|p| Protocol::from_name(p.trim()))
+        .ok_or_else(|| unsupported(offered))
+}
 
+#[allow(clippy::too_many_arguments)]
 pub async fn ws_handler(
     ws: WebSocketUpgrade,
     Path(room_id): Path<Uuid>,
-    State(store): State<MemoryStore>,
+    Query(params): Query<WsParams>,
+    State(store): State<SqliteSessionStore>,
     State(rooms): State<Rooms>,
     user: User,
+    headers: HeaderMap,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
 ) -> axum::response::Result<impl IntoResponse> {
@@ -34,22 +78,64 @@ pub async fn ws_handler(
     let user_id = user.id;
     tracing::info!("`{user_id} with agent {user_agent}` at {addr} connected.");
 
+    let protocol = match negotiate_protocol(&headers, params.protocol.as_deref()) {
+        Ok(protocol) => protocol,
+        Err(e) => return Err(ErrorResponse::from((StatusCode::BAD_REQUEST, e))),
+    };
+
     let Some(room) = rooms.get(&room_id) else {
         return Err(ErrorResponse::from(StatusCode::NOT_FOUND));
     };
 
-    let user_receiver = room.read().await.receiver.activate_cloned();
+    let outbox = room.read().await.outbox.clone();
 
+    // echoed back only if the client offered it
     axum::response::Result::Ok(
-        ws.on_upgrade(move |socket| handle_socket(socket, addr, user_receiver, user_id)),
+        ws.protocols([protocol.name()])
+            .on_upgrade(move |socket| handle_socket(socket, addr, outbox, user_id, protocol)),
     )
 }
 
+// why the server closes the socket
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CloseReason {
+    Normal,
+    RoomClosed,
+    TooManyErrors,
+    TooSlow,
+    RoomStopped,
+    Internal,
+}
+
+impl CloseReason {
+    fn frame(self) -> CloseFrame<'static> {
+        let (code, reason) = match self {
+            CloseReason::Normal => (close_code::NORMAL, "Goodbye"),
+            CloseReason::RoomClosed => (close_code::NORMAL, "Room closed"),
+            CloseReason::TooManyErrors => (close_code::POLICY, "Too many protocol errors"),
+            CloseReason::TooSlow => (close_code::AGAIN, "Connection too slow"),
+            CloseReason::RoomStopped => (close_code::RESTART, "Room stopped"),
+            CloseReason::Internal => (close_code::ERROR, "Internal error"),
+        };
+        CloseFrame {
+            code,
+            reason: Cow::from(reason),
+        }
+    }
+}
+
+// from the receiving half of the socket to the sending half
+enum Outgoing {
+    Message(Box<RoomMessage>),
+    Close(CloseReason),
+}
+
 async fn handle_socket(
     mut socket: WebSocket,
     who: SocketAddr,
-    mut user_receiver: Receiver<RoomMessage>,
+    outbox: Outbox,
     user_id: UserId,
+    protocol: Protocol,
 ) {
     if socket.send(Message::Ping(vec![1, 2, 3])).await.is_ok() {
         tracing::info!("Pinged {}...", who);
@@ -58,10 +144,18 @@ async fn handle_socket(
         return;
     }
 
+    let (outgoing_sender, mut outgoing_receiver) = mpsc::channel(MAX_PROTOCOL_ERRORS + 1);
+    let mut protocol_errors = 0;
+
     if let Some(msg) = socket.recv().await {
         if let Ok(msg) = msg {
-            if process_message(&msg, who).is_break() {
-                return;
+            match process_message(&msg, who, user_id, protocol.encoding) {
+                ControlFlow::Break(_) => return,
+                ControlFlow::Continue(Err(error)) => {
+                    protocol_errors += 1;
+                    let _ = outgoing_sender.try_send(Outgoing::Message(Box::new(error)));
+                }
+                ControlFlow::Continue(Ok(_)) => {}
             }
         } else {
             tracing::info!("client {who} abruptly disconnected");
@@ -69,9 +163,13 @@ async fn handle_socket(
         }
     }
 
-    let user_sender = user_receiver.new_sender();
+    // private messages of the user, e.g. the cards, only come through this one
+    let mut private_receiver = outbox.register(user_id);
+    let mut public_receiver = outbox.subscribe();
+    let user_outbox = outbox.clone();
+    let resume_outbox = outbox.clone();
 
-    tracing::debug!("is channel closed: {}", user_sender.is_closed());
+    notify_room(&outbox, user_id, RoomMessageType::Connected).await;
 
     let (mut sender, mut receiver) = socket.split();
 
@@ -79,80 +177,116 @@ async fn handle_socket(
         async fn send_msg(
             sender: &mut SplitSink<WebSocket, Message>,
             msg: RoomMessage,
-        ) -> ControlFlow<()> {
-            let Ok(msg) = serde_json::to_string(&msg) else {
-                tracing::error!("could not serialize msg {msg:?}!!");
-                return ControlFlow::Break(());
+            protocol: Protocol,
+        ) -> ControlFlow<CloseReason> {
+            let msg = match wire::encode(protocol, &msg) {
+                Some(Ok(Frame::Text(text))) => Message::Text(text),
+                Some(Ok(Frame::Binary(bytes))) => Message::Binary(bytes),
+                Some(Err(e)) => {
+                    tracing::error!("could not serialize msg {msg:?}: {e}!!");
+                    return ControlFlow::Break(CloseReason::Internal);
+                }
+                None => {
+                    tracing::debug!("{msg:?} has no {} shape", protocol.name());
+                    return ControlFlow::Continue(());
+                }
             };
-            if let Err(e) = sender.send(Message::Text(msg)).await {
+            if let Err(e) = sender.send(msg).await {
                 tracing::error!("Could not send message back due to {e}!!!");
-                return ControlFlow::Break(());
+                return ControlFlow::Break(CloseReason::Normal);
             }
             ControlFlow::Continue(())
         }
 
-        loop {
-            match user_receiver.recv_direct().await {
-                Ok(msg) => {
-                    if let Some(to_user_id) = &msg.to_user_id {
-                        // check if the message is for this user
-                        if to_user_id != &user_id {
-                            // it is not for you
-                            continue;
-                        }
-                        if send_msg(&mut sender, msg).await.is_break() {
-                            break;
-                        };
-                    } else if msg.from_user_id.is_none() {
-                        // message comes from system and is not for anyone in particular, broadcast it
-                        if send_msg(&mut sender, msg).await.is_break() {
-                            break;
-                        };
+        // last public event sent to the client
+        let mut last_seq = 0;
+        let reason = loop {
+            let msg = tokio::select! {
+                msg = private_receiver.recv() => match msg {
+                    Some(msg) => msg,
+                    // the room stopped delivering to this socket
+                    None => break CloseReason::TooSlow,
+                },
+                msg = public_receiver.recv_direct() => match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Overflowed(skipped)) => {
+                        // the client missed some events, replay them
+                        tracing::warn!("{user_id} skipped {skipped} message(s)");
+                        notify_room(&resume_outbox, user_id, RoomMessageType::Resume { last_seq })
+                            .await;
+                        continue;
                     }
-                }
-                Err(e) => {
-                    tracing::error!("user_receiver stopped {e}");
-                    break;
-                }
+                    Err(e) => {
+                        tracing::error!("public_receiver stopped {e}");
+                        break CloseReason::RoomStopped;
+                    }
+                },
+                outgoing = outgoing_receiver.recv() => match outgoing {
+                    Some(Outgoing::Message(msg)) => *msg,
+                    Some(Outgoing::Close(reason)) => break reason,
+                    None => break CloseReason::Normal,
+                },
+            };
+            if msg.to_user_id.is_none() {
+                last_seq = last_seq.max(msg.seq.unwrap_or_default());
             }
-        }
-        user_receiver.deactivate();
-
-        if let Err(e) = sender
-            .send(Message::Close(Some(CloseFrame {
-                code: axum::extract::ws::close_code::NORMAL,
-                reason: Cow::from("Goodbye"),
-            })))
-            .await
-        {
+            let room_closed = msg.msg_type == RoomMessageType::RoomClosed;
+            if let ControlFlow::Break(reason) = send_msg(&mut sender, msg, protocol).await {
+                break reason;
+            }
+            if room_closed {
+                break CloseReason::RoomClosed;
+            }
+        };
+        public_receiver.deactivate();
+
+        tracing::info!("closing socket of {user_id}: {reason:?}");
+        if let Err(e) = sender.send(Message::Close(Some(reason.frame()))).await {
             tracing::warn!("Could not send Close due to {}, probably it is ok?", e);
         }
     });
     let mut room_receive_task = tokio::spawn(async move {
-        while let Some(Ok(msg)) = receiver.next().await {
-            // print message and break if instructed to do so
-            match process_message(&msg, who) {
-                ControlFlow::Continue(Some(room_msg)) => {
-                    if let Err(e) = user_sender
-                        .broadcast_direct(RoomMessage {
+        let reason = loop {
+            let Some(Ok(msg)) = receiver.next().await else {
+                break CloseReason::Normal;
+            };
+            match process_message(&msg, who, user_id, protocol.encoding) {
+                ControlFlow::Continue(Ok(Some(room_msg))) => {
+                    if let Err(e) = user_outbox
+                        .send_to_room(RoomMessage {
                             from_user_id: Some(user_id),
                             ..room_msg
                         })
                         .await
                     {
                         tracing::error!("could not send message to room {e:?}, message: {msg:?}");
-                        break;
+                        break CloseReason::RoomStopped;
                     }
                 }
-                ControlFlow::Continue(None) => {
+                ControlFlow::Continue(Ok(None)) => {
                     tracing::warn!("continue... although message wasn't a room message :{msg:?}");
                 }
-                ControlFlow::Break(_) => break,
+                ControlFlow::Continue(Err(error)) => {
+                    protocol_errors += 1;
+                    if protocol_errors > MAX_PROTOCOL_ERRORS {
+                        break CloseReason::TooManyErrors;
+                    }
+                    if outgoing_sender
+                        .send(Outgoing::Message(Box::new(error)))
+                        .await
+                        .is_err()
+                    {
+                        break CloseReason::Normal;
+                    }
+                }
+                ControlFlow::Break(_) => break CloseReason::Normal,
             }
-        }
+        };
+        // the sending half closes the socket
+        let _ = outgoing_sender.send(Outgoing::Close(reason)).await;
     });
 
-    // If any one of the tasks exit, abort the other.
+    // If any one of the tasks exit, stop the other.
     tokio::select! {
         rv_a = (&mut room_send_task) => {
             match rv_a {
@@ -166,31 +300,88 @@ async fn handle_socket(
                 Ok(_) => tracing::info!("stop receiving messages from {who}"),
                 Err(b) => tracing::error!("Error receiving messages {b:?}")
             }
-            room_send_task.abort();
+            // give it a chance to send the close frame
+            if timeout(Duration::from_secs(CLOSE_TIMEOUT_SECS), &mut room_send_task)
+                .await
+                .is_err()
+            {
+                room_send_task.abort();
+            }
         }
     }
 
+    notify_room(&outbox, user_id, RoomMessageType::Disconnected).await;
+
     // returning from the handler closes the websocket connection
     tracing::info!("Websocket context {} destroyed", who);
 }
 
-fn process_message(msg: &Message, who: SocketAddr) -> ControlFlow<(), Option<RoomMessage>> {
+// private, so the client doesn't take it for an event it already has
+fn protocol_error(user_id: UserId, code: ProtocolErrorCode, detail: impl ToString) -> RoomMessage {
+    RoomMessage::to(
+        user_id,
+        RoomMessageType::ProtocolError {
+            code,
+            detail: detail.to_string(),
+        },
+    )
+}
+
+// message to the room on behalf of the user, e.g. when one of its sockets opens or closes
+async fn notify_room(outbox: &Outbox, user_id: UserId, msg_type: RoomMessageType) {
+    if let Err(e) = outbox
+        .send_to_room(RoomMessage::from_user(user_id, msg_type))
+        .await
+    {
+        tracing::error!("could not send presence of {user_id} to room {e:?}");
+    }
+}
+
+// a message the client should not have sent is answered with a protocol error
+fn process_message(
+    msg: &Message,
+    who: SocketAddr,
+    user_id: UserId,
+    encoding: Encoding,
+) -> ControlFlow<(), Result<Option<RoomMessage>, RoomMessage>> {
     match msg {
         Message::Text(t) => {
             tracing::debug!(">>> {} sent str: {:?}", who, t);
             match serde_json::from_str::<RoomMessage>(t) {
-                Ok(msg) => return ControlFlow::Continue(Some(msg)),
+                Ok(msg) => return ControlFlow::Continue(Ok(Some(msg))),
                 Err(e) => {
                     tracing::error!("could not deserialize message {e}");
-                    return ControlFlow::Break(());
+                    return ControlFlow::Continue(Err(protocol_error(
+                        user_id,
+                        ProtocolErrorCode::InvalidMessage,
+                        e,
+                    )));
                 }
             }
         }
         Message::Binary(d) => {
-            tracing::error!(
-                "Binary message was sent by {who} with a length of {} bytes: {d:?}",
+            tracing::debug!(
+                ">>> {who} sent a binary message with a length of {} bytes",
                 d.len(),
             );
+            match wire::decode_binary(encoding, d) {
+                Some(Ok(msg)) => return ControlFlow::Continue(Ok(Some(msg))),
+                Some(Err(e)) => {
+                    tracing::error!("could not decode binary message {e}");
+                    return ControlFlow::Continue(Err(protocol_error(
+                        user_id,
+                        ProtocolErrorCode::InvalidMessage,
+                        e,
+                    )));
+                }
+                None => {
+                    return ControlFlow::Continue(Err(protocol_error(
+                        user_id,
+                        ProtocolErrorCode::UnsupportedFrame,
+                        "binary frames need a binary encoding, e.g hearts.v2+msgpack",
+                    )));
+                }
+            }
         }
         Message::Close(c) => {
             if let Some(cf) = c {
@@ -213,5 +404,114 @@ fn process_message(msg: &Message, who: SocketAddr) -> ControlFlow<(), Option<Roo
             tracing::debug!(">>> {} sent ping with {:?}", who, v);
         }
     }
-    ControlFlow::Continue(None)
+    ControlFlow::Continue(Ok(None))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{net::SocketAddr, ops::ControlFlow};
+
+    use axum::{
+        extract::ws::Message,
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderMap, HeaderValue},
+    };
+
+    use uuid::Uuid;
+
+    use super::{negotiate_protocol, process_message, CloseReason};
+    use crate::data::{
+        wire::{self, Encoding, Frame, Protocol, ProtocolVersion},
+        ProtocolErrorCode, RoomMessageType,
+    };
+
+    fn who() -> SocketAddr {
+        "127.0.0.1:8080".parse().unwrap()
+    }
+
+    #[test]
+    fn test_malformed_message() {
+        let ControlFlow::Continue(Err(error)) = process_message(
+            &Message::Text("{\"msgType\": \"nope\"}".into()),
+            who(),
+            Uuid::new_v4(),
+            Encoding::Json,
+        ) else {
+            panic!("expected a protocol error");
+        };
+        assert!(matches!(
+            error.msg_type,
+            RoomMessageType::ProtocolError {
+                code: ProtocolErrorCode::InvalidMessage,
+                ..
+            }
+        ));
+        let ControlFlow::Continue(Err(error)) = process_message(
+            &Message::Binary(vec![1]),
+            who(),
+            Uuid::new_v4(),
+            Encoding::Json,
+        ) else {
+            panic!("expected a protocol error");
+        };
+        assert!(matches!(
+            error.msg_type,
+            RoomMessageType::ProtocolError {
+                code: ProtocolErrorCode::UnsupportedFrame,
+                ..
+            }
+        ));
+        assert!(
+            process_message(&Message::Close(None), who(), Uuid::new_v4(), Encoding::Json)
+                .is_break()
+        );
+    }
+
+    #[test]
+    fn test_protocol_error_is_private() {
+        let user_id = Uuid::new_v4();
+        let ControlFlow::Continue(Err(error)) = process_message(
+            &Message::Text("nope".into()),
+            who(),
+            user_id,
+            Encoding::Json,
+        ) else {
+            panic!("expected a protocol error");
+        };
+        // the client skips public events up to the last seq it has, but not private messages
+        let Some(Ok(Frame::Text(json))) = wire::encode(Protocol::DEFAULT, &error) else {
+            panic!("expected a text frame");
+        };
+        let json: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(user_id.to_string(), json["toUserId"]);
+    }
+
+    #[test]
+    fn test_negotiate_protocol() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(Ok(Protocol::DEFAULT), negotiate_protocol(&headers, None));
+        headers.insert(
+            SEC_WEBSOCKET_PROTOCOL,
+            HeaderValue::from_static("hearts.v3, hearts.v2+msgpack"),
+        );
+        assert_eq!(
+            Ok(Protocol {
+                version: ProtocolVersion::V2,
+                encoding: Encoding::MessagePack
+            }),
+            negotiate_protocol(&headers, None)
+        );
+        assert_eq!(
+            Ok(Protocol::DEFAULT),
+            negotiate_protocol(&headers, Some("hearts.v1"))
+        );
+        assert!(negotiate_protocol(&headers, Some("hearts.v3")).is_err());
+        headers.insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("chat"));
+        assert!(negotiate_protocol(&headers, None).is_err());
+    }
+
+    #[test]
+    fn test_close_reason() {
+        assert_eq!(1008, CloseReason::TooManyErrors.frame().code);
+        assert_eq!(1000, CloseReason::Normal.frame().code);
+    }
 }